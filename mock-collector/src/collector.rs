@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::http::{Request, Response};
+
+// Kinds of records other than events the collector stores, each received on
+// /v1/<kind> and /v1/<kind>/batch
+const RECORD_KINDS: &[&str] = &["actions", "users", "companies"];
+
+// Settings read from the environment at start up, see main.rs
+pub struct Settings {
+    pub application_id: Option<String>,
+    pub config: Value,
+    pub rules: Value,
+    pub latency: Duration,
+    pub error_status: u16,
+    pub error_every: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            application_id: None,
+            config: json!({
+                "org_id": "mock-org",
+                "app_id": "mock-app",
+                "sample_rate": 100,
+                "block_bot_traffic": false,
+                "user_sample_rate": {},
+                "company_sample_rate": {},
+                "user_rules": {},
+                "company_rules": {},
+                "ip_addresses_blocked_by_name": {},
+                "regex_config": [],
+                "billing_config_jsons": {}
+            }),
+            rules: json!([]),
+            latency: Duration::from_millis(0),
+            error_status: 500,
+            error_every: 0,
+        }
+    }
+}
+
+// A versioned response body, the etag changes every time the body is replaced
+struct Versioned {
+    name: &'static str,
+    body: Value,
+    version: usize,
+}
+
+impl Versioned {
+    fn new(name: &'static str, body: Value) -> Versioned {
+        Versioned {
+            name,
+            body,
+            version: 1,
+        }
+    }
+
+    fn etag(&self) -> String {
+        format!("mock-{}-{}", self.name, self.version)
+    }
+
+    fn replace(&mut self, body: Value) {
+        self.body = body;
+        self.version += 1;
+    }
+}
+
+struct State {
+    config: Versioned,
+    rules: Versioned,
+    events: Vec<Value>,
+    batches: usize,
+    records: HashMap<&'static str, Vec<Value>>,
+}
+
+pub struct Collector {
+    application_id: Option<String>,
+    latency: Duration,
+    error_status: u16,
+    error_every: usize,
+    state: Mutex<State>,
+}
+
+impl Collector {
+    pub fn new(settings: Settings) -> Collector {
+        Collector {
+            application_id: settings.application_id,
+            latency: settings.latency,
+            error_status: settings.error_status,
+            error_every: settings.error_every,
+            state: Mutex::new(State {
+                config: Versioned::new("config", settings.config),
+                rules: Versioned::new("rules", settings.rules),
+                events: Vec::new(),
+                batches: 0,
+                records: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        // The /_mock endpoints let tests inspect and change the collector
+        if request.path.starts_with("/_mock/") {
+            return self.handle_control(request);
+        }
+
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        if let Some(application_id) = &self.application_id {
+            if request.header("x-moesif-application-id") != Some(application_id.as_str()) {
+                return Response::json(401, &json!({"error": "invalid application id"}));
+            }
+        }
+
+        let path = request.path.split('?').next().unwrap_or_default();
+        match (request.method.as_str(), path) {
+            ("POST", "/v1/events/batch") => self.receive_events(request),
+            ("POST", "/v1/events") => self.receive_events(request),
+            ("GET", "/v1/config") => {
+                let state = self.state.lock().unwrap();
+                Response::json(200, &state.config.body)
+                    .with_header("x-moesif-config-etag", &state.config.etag())
+            }
+            ("GET", "/v1/rules") => {
+                let state = self.state.lock().unwrap();
+                Response::json(200, &state.rules.body)
+                    .with_header("x-moesif-rules-etag", &state.rules.etag())
+            }
+            ("POST", path) => {
+                match record_kind(path.strip_suffix("/batch").unwrap_or(path), "/v1/") {
+                    Some(kind) => self.receive_records(kind, request),
+                    None => Response::json(404, &json!({"error": "not found"})),
+                }
+            }
+            _ => Response::json(404, &json!({"error": "not found"})),
+        }
+    }
+
+    fn receive_events(&self, request: &Request) -> Response {
+        let events = match parse_batch(request) {
+            Ok(events) => events,
+            Err(response) => return response,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.batches += 1;
+        // every error_every-th batch fails and its events are not stored
+        if self.error_every > 0 && state.batches % self.error_every == 0 {
+            return Response::json(self.error_status, &json!({"error": "injected error"}));
+        }
+        state.events.extend(events);
+        Response::json(201, &json!({}))
+            .with_header("x-moesif-config-etag", &state.config.etag())
+            .with_header("x-moesif-rules-etag", &state.rules.etag())
+    }
+
+    fn receive_records(&self, kind: &'static str, request: &Request) -> Response {
+        let records = match parse_batch(request) {
+            Ok(records) => records,
+            Err(response) => return response,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.records.entry(kind).or_default().extend(records);
+        Response::json(201, &json!({}))
+    }
+
+    fn handle_control(&self, request: &Request) -> Response {
+        let mut state = self.state.lock().unwrap();
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/_mock/events") => Response::json(200, &Value::Array(state.events.clone())),
+            ("DELETE", "/_mock/events") => {
+                state.events.clear();
+                state.batches = 0;
+                Response::json(200, &json!({}))
+            }
+            ("PUT", "/_mock/config") | ("PUT", "/_mock/rules") => {
+                let body = match serde_json::from_slice::<Value>(&request.body) {
+                    Ok(body) => body,
+                    Err(e) => return Response::json(400, &json!({"error": e.to_string()})),
+                };
+                let versioned = if request.path == "/_mock/config" {
+                    &mut state.config
+                } else {
+                    &mut state.rules
+                };
+                versioned.replace(body);
+                Response::json(200, &json!({"etag": versioned.etag()}))
+            }
+            (method, path) => match (method, record_kind(path, "/_mock/")) {
+                ("GET", Some(kind)) => {
+                    let records = state.records.get(kind).cloned().unwrap_or_default();
+                    Response::json(200, &Value::Array(records))
+                }
+                ("DELETE", Some(kind)) => {
+                    state.records.remove(kind);
+                    Response::json(200, &json!({}))
+                }
+                _ => Response::json(404, &json!({"error": "not found"})),
+            },
+        }
+    }
+}
+
+// The record kind of a path such as /v1/actions
+fn record_kind(path: &str, prefix: &str) -> Option<&'static str> {
+    let name = path.strip_prefix(prefix)?;
+    RECORD_KINDS.iter().copied().find(|kind| *kind == name)
+}
+
+// A JSON object or array of objects
+fn parse_batch(request: &Request) -> Result<Vec<Value>, Response> {
+    match serde_json::from_slice::<Value>(&request.body) {
+        Ok(Value::Array(records)) => Ok(records),
+        Ok(record @ Value::Object(_)) => Ok(vec![record]),
+        _ => Err(Response::json(
+            400,
+            &json!({"error": "body must be a JSON object or array of objects"}),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: vec![("x-moesif-application-id".to_string(), "app".to_string())],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn stores_event_batches() {
+        let collector = Collector::new(Settings::default());
+        let response =
+            collector.handle(&request("POST", "/v1/events/batch", r#"[{"a":1},{"a":2}]"#));
+        assert_eq!(response.status, 201);
+        assert!(response.headers.contains(&(
+            "x-moesif-config-etag".to_string(),
+            "mock-config-1".to_string()
+        )));
+
+        let events = collector.handle(&request("GET", "/_mock/events", ""));
+        assert_eq!(body(&events), json!([{"a": 1}, {"a": 2}]));
+
+        collector.handle(&request("DELETE", "/_mock/events", ""));
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/events", ""))),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn stores_actions_and_profiles() {
+        let collector = Collector::new(Settings::default());
+        let batch = r#"[{"action_name": "Signed Up"}]"#;
+        assert_eq!(
+            collector
+                .handle(&request("POST", "/v1/actions/batch", batch))
+                .status,
+            201
+        );
+        let single = r#"{"action_name": "Logged In"}"#;
+        assert_eq!(
+            collector
+                .handle(&request("POST", "/v1/actions", single))
+                .status,
+            201
+        );
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/actions", ""))),
+            json!([{"action_name": "Signed Up"}, {"action_name": "Logged In"}])
+        );
+        // actions are kept apart from events
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/events", ""))),
+            json!([])
+        );
+        collector.handle(&request("DELETE", "/_mock/actions", ""));
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/actions", ""))),
+            json!([])
+        );
+        let users = r#"[{"user_id": "u-1", "metadata": {"plan": "pro"}}]"#;
+        collector.handle(&request("POST", "/v1/users/batch", users));
+        let companies = r#"[{"company_id": "acme", "metadata": {}}]"#;
+        collector.handle(&request("POST", "/v1/companies/batch", companies));
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/users", ""))),
+            json!([{"user_id": "u-1", "metadata": {"plan": "pro"}}])
+        );
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/companies", ""))),
+            json!([{"company_id": "acme", "metadata": {}}])
+        );
+        assert_eq!(
+            collector
+                .handle(&request("POST", "/v1/unknown", "{}"))
+                .status,
+            404
+        );
+    }
+
+    #[test]
+    fn replacing_rules_changes_the_etag() {
+        let collector = Collector::new(Settings::default());
+        let response = collector.handle(&request("PUT", "/_mock/rules", r#"[{"_id": "r1"}]"#));
+        assert_eq!(body(&response), json!({"etag": "mock-rules-2"}));
+
+        let rules = collector.handle(&request("GET", "/v1/rules", ""));
+        assert_eq!(body(&rules), json!([{"_id": "r1"}]));
+        assert!(rules.headers.contains(&(
+            "x-moesif-rules-etag".to_string(),
+            "mock-rules-2".to_string()
+        )));
+    }
+
+    #[test]
+    fn injects_errors_and_checks_the_application_id() {
+        let collector = Collector::new(Settings {
+            application_id: Some("app".to_string()),
+            error_every: 2,
+            error_status: 503,
+            ..Default::default()
+        });
+        assert_eq!(
+            collector
+                .handle(&request("POST", "/v1/events/batch", "[{}]"))
+                .status,
+            201
+        );
+        assert_eq!(
+            collector
+                .handle(&request("POST", "/v1/events/batch", "[{}]"))
+                .status,
+            503
+        );
+        assert_eq!(
+            body(&collector.handle(&request("GET", "/_mock/events", ""))),
+            json!([{}])
+        );
+
+        let mut wrong_app = request("GET", "/v1/config", "");
+        wrong_app.headers.clear();
+        assert_eq!(collector.handle(&wrong_app).status, 401);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{self, BufRead, Write};
+
+// Just enough HTTP/1.1 for Envoy's upstream connections to the collector:
+// Content-Length bodies and keep-alive, no chunked encoding.
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: &serde_json::Value) -> Response {
+        Response {
+            status,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(body).unwrap(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Returns None when the client closed the connection before a new request
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("connection closed in headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        match header.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()))
+            }
+            None => return Err(invalid("malformed header")),
+        }
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(invalid("chunked requests are not supported"));
+    }
+    let content_length = match request.header("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| invalid("bad content-length"))?,
+        None => 0,
+    };
+    request.body = vec![0; content_length];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+pub fn write_response<W: Write>(writer: &mut W, response: &Response) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    )?;
+    for (name, value) in &response.headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    write!(writer, "content-length: {}\r\n\r\n", response.body.len())?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keep_alive_requests() {
+        let raw = b"POST /v1/events/batch HTTP/1.1\r\nHost: mock\r\nContent-Length: 2\r\n\r\n[]\
+GET /v1/config HTTP/1.1\r\nX-Moesif-Application-Id: app\r\n\r\n";
+        let mut reader = &raw[..];
+
+        let first = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(first.method, "POST");
+        assert_eq!(first.path, "/v1/events/batch");
+        assert_eq!(first.header("HOST"), Some("mock"));
+        assert_eq!(first.body, b"[]");
+
+        let second = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(second.path, "/v1/config");
+        assert_eq!(second.header("x-moesif-application-id"), Some("app"));
+        assert!(second.body.is_empty());
+
+        assert!(read_request(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn writes_content_length() {
+        let mut out = Vec::new();
+        let response = Response::json(201, &serde_json::json!({})).with_header("x-test", "1");
+        write_response(&mut out, &response).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\nx-test: 1\r\ncontent-length: 2\r\n\r\n{}"
+        );
+    }
+}
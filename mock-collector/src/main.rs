@@ -0,0 +1,94 @@
+// A local stand-in for the Moesif collector API so the plugin can be run end to
+// end without a Moesif account. Configured with environment variables:
+//
+//   LISTEN_ADDR     address to listen on (0.0.0.0:8080)
+//   APPLICATION_ID  reject requests without this x-moesif-application-id
+//   CONFIG_FILE     JSON file served from /v1/config
+//   RULES_FILE      JSON file served from /v1/rules
+//   LATENCY_MS      delay before answering each API request
+//   ERROR_EVERY     fail every Nth event batch, 0 disables errors
+//   ERROR_STATUS    status of the injected errors (500)
+//
+// Received events are available from GET /_mock/events and cleared with
+// DELETE /_mock/events, actions and user and company profiles likewise from
+// /_mock/actions, /_mock/users and /_mock/companies. PUT /_mock/config and
+// PUT /_mock/rules replace the served JSON and bump its etag.
+mod collector;
+mod http;
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use collector::{Collector, Settings};
+
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> Result<T, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{} has invalid value {:?}", name, value).into()),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_json(name: &str, default: serde_json::Value) -> Result<serde_json::Value, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(path) => {
+            let contents = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        Err(_) => Ok(default),
+    }
+}
+
+fn settings_from_env() -> Result<Settings, Box<dyn Error>> {
+    let defaults = Settings::default();
+    Ok(Settings {
+        application_id: env::var("APPLICATION_ID").ok(),
+        config: env_json("CONFIG_FILE", defaults.config)?,
+        rules: env_json("RULES_FILE", defaults.rules)?,
+        latency: Duration::from_millis(env_parse("LATENCY_MS", 0)?),
+        error_status: env_parse("ERROR_STATUS", defaults.error_status)?,
+        error_every: env_parse("ERROR_EVERY", defaults.error_every)?,
+    })
+}
+
+fn serve(collector: &Collector, stream: TcpStream) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = http::read_request(&mut reader)? {
+        let response = collector.handle(&request);
+        println!("{} {} -> {}", request.method, request.path, response.status);
+        http::write_response(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let addr = env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+    let collector = Arc::new(Collector::new(settings_from_env()?));
+    let listener = TcpListener::bind(&addr)?;
+    println!("Mock Moesif collector listening on {}", addr);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let collector = Arc::clone(&collector);
+        thread::spawn(move || {
+            if let Err(e) = serve(&collector, stream) {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::event::Event;
+use crate::metadata::MetadataConfig;
+
+// {{path}} placeholders of action names
+const PLACEHOLDER: &str = r"\{\{\s*([^{}]+?)\s*\}\}";
+
+// Request headers left out of actions, actions are shown to more people in
+// Moesif than the events they come from
+const CREDENTIAL_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+// An entry of the `actions` section of the plugin configuration. Every logged
+// request matching all the conditions is also sent to Moesif as an action.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActionConfig {
+    // the action name, where {{path}} is replaced by the value of an event path
+    // such as request.route or response.headers.x-plan
+    pub name: String,
+    // event path -> regex matched against its value, as in regex_config
+    #[serde(default, rename = "match")]
+    pub conditions: HashMap<String, String>,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+}
+
+impl ActionConfig {
+    fn validate(&self, field: &str, errors: &mut Vec<String>) {
+        if self.name.trim().is_empty() {
+            errors.push(format!("{}.name must not be empty", field));
+        }
+        for (path, pattern) in &self.conditions {
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(format!(
+                    "{}.match.{} {:?} is not a valid regex: {}",
+                    field, path, pattern, e
+                ));
+            }
+        }
+        self.metadata
+            .validate(&format!("{}.metadata", field), errors);
+    }
+}
+
+pub fn validate_actions(actions: &[ActionConfig], errors: &mut Vec<String>) {
+    for (i, action) in actions.iter().enumerate() {
+        action.validate(&format!("actions[{}]", i), errors);
+    }
+}
+
+// An ActionConfig with its regexes compiled
+#[derive(Clone)]
+struct CompiledAction {
+    name: String,
+    conditions: Vec<(String, Regex)>,
+    metadata: MetadataConfig,
+}
+
+impl CompiledAction {
+    fn matches(&self, event: &Event) -> bool {
+        self.conditions.iter().all(|(path, regex)| {
+            event
+                .lookup(path)
+                .is_some_and(|value| regex.is_match(&value))
+        })
+    }
+}
+
+// The actions of the plugin configuration compiled once at configuration time
+#[derive(Clone)]
+pub struct ActionMatcher {
+    actions: Vec<CompiledAction>,
+    placeholder: Regex,
+}
+
+impl Default for ActionMatcher {
+    fn default() -> ActionMatcher {
+        ActionMatcher::new(&[])
+    }
+}
+
+impl ActionMatcher {
+    // Actions with invalid regexes are dropped here, they are reported by validate_actions
+    pub fn new(actions: &[ActionConfig]) -> ActionMatcher {
+        ActionMatcher {
+            actions: actions
+                .iter()
+                .filter_map(|action| {
+                    let conditions = action
+                        .conditions
+                        .iter()
+                        .map(|(path, pattern)| Some((path.clone(), Regex::new(pattern).ok()?)))
+                        .collect::<Option<Vec<_>>>()?;
+                    Some(CompiledAction {
+                        name: action.name.clone(),
+                        conditions,
+                        metadata: action.metadata.clone(),
+                    })
+                })
+                .collect(),
+            placeholder: Regex::new(PLACEHOLDER).unwrap(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // The Moesif actions of every matching entry for a logged event. get_property
+    // reads an Envoy attribute for the action metadata.
+    pub fn actions<F>(&self, event: &Event, get_property: F) -> Vec<Value>
+    where
+        F: Fn(Vec<&str>) -> Option<Vec<u8>>,
+    {
+        self.actions
+            .iter()
+            .filter(|action| action.matches(event))
+            .filter_map(|action| {
+                let name = self.name(&action.name, event);
+                if name.trim().is_empty() {
+                    log::debug!("Skipping action {:?} with an empty name", action.name);
+                    return None;
+                }
+                let metadata = action.metadata.values(event, &get_property);
+                Some(action_json(name, metadata, event))
+            })
+            .collect()
+    }
+
+    // Replaces the placeholders of an action name, missing values with ""
+    fn name(&self, template: &str, event: &Event) -> String {
+        self.placeholder
+            .replace_all(template, |captures: &Captures| {
+                event.lookup(&captures[1]).unwrap_or_default()
+            })
+            .into_owned()
+    }
+}
+
+// The Moesif action model
+fn action_json(name: String, metadata: Map<String, Value>, event: &Event) -> Value {
+    let request = &event.request;
+    let headers: Map<String, Value> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| !CREDENTIAL_HEADERS.contains(&name.to_lowercase().as_str()))
+        .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+        .collect();
+    let mut action = json!({
+        "action_name": name,
+        "request": {
+            "time": request.time,
+            "uri": request.uri,
+            "verb": request.verb,
+            "headers": headers,
+        },
+    });
+    if let Some(ip_address) = &request.ip_address {
+        action["request"]["ip_address"] = json!(ip_address);
+    }
+    if let Some(user_agent) = request.headers.get("user-agent") {
+        action["request"]["user_agent_string"] = json!(user_agent);
+    }
+    if let Some(user_id) = &event.user_id {
+        action["user_id"] = json!(user_id);
+    }
+    if let Some(company_id) = &event.company_id {
+        action["company_id"] = json!(company_id);
+    }
+    if let Some(session_token) = &event.session_token {
+        action["session_token"] = json!(session_token);
+    }
+    if !metadata.is_empty() {
+        action["metadata"] = Value::Object(metadata);
+    }
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{RequestInfo, ResponseInfo};
+
+    #[test]
+    fn matching_requests_become_named_actions() {
+        let actions: Vec<ActionConfig> = serde_json::from_value(json!([
+            {
+                "name": "Signed Up",
+                "match": {"request.verb": "^POST$", "request.route": "^/signup$", "response.status": "^201$"},
+                "metadata": {"plan": {"request_header": "x-plan"}}
+            },
+            {"name": "Viewed {{ request.headers.x-product }}{{user_id}}", "match": {"request.route": "^/products/"}},
+            {"name": "{{company_id}}"}
+        ]))
+        .unwrap();
+        let mut errors = Vec::new();
+        validate_actions(&actions, &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let matcher = ActionMatcher::new(&actions);
+
+        let mut event = Event {
+            request: RequestInfo {
+                time: "2024-01-02T03:04:05+00:00".to_string(),
+                verb: "POST".to_string(),
+                uri: "/signup?ref=ad".to_string(),
+                headers: vec![
+                    ("x-plan".to_string(), "pro".to_string()),
+                    ("authorization".to_string(), "Bearer secret".to_string()),
+                    ("cookie".to_string(), "session=secret".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            },
+            response: Some(ResponseInfo {
+                status: 201,
+                ..Default::default()
+            }),
+            user_id: Some("u-1".to_string()),
+            ..Default::default()
+        };
+        // the third action's name has no value without a company, and credentials
+        // are left out of the headers
+        assert_eq!(
+            matcher.actions(&event, |_| None),
+            vec![json!({
+                "action_name": "Signed Up",
+                "request": {
+                    "time": "2024-01-02T03:04:05+00:00",
+                    "uri": "/signup?ref=ad",
+                    "verb": "POST",
+                    "headers": {"x-plan": "pro"}
+                },
+                "user_id": "u-1",
+                "metadata": {"plan": "pro"}
+            })]
+        );
+
+        event.request.uri = "/products/1".to_string();
+        event.company_id = Some("acme".to_string());
+        let names: Vec<Value> = matcher
+            .actions(&event, |_| None)
+            .into_iter()
+            .map(|action| action["action_name"].clone())
+            .collect();
+        assert_eq!(names, vec![json!("Viewed u-1"), json!("acme")]);
+
+        let invalid: Vec<ActionConfig> =
+            serde_json::from_value(json!([{"name": " ", "match": {"request.route": "("}}]))
+                .unwrap();
+        let mut errors = Vec::new();
+        validate_actions(&invalid, &mut errors);
+        assert_eq!(errors.len(), 2);
+    }
+}
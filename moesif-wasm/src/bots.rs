@@ -0,0 +1,176 @@
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+
+// User agents of well known crawlers and headless browsers, matched case
+// insensitively
+const BOT_USER_AGENT_PATTERNS: &[&str] = &[
+    // crawlers and link previews, a bare "bot" suffix also names devices such as Cubot
+    r"bot/\d",
+    r"\bbot\b",
+    r"slackbot",
+    r"crawler",
+    r"spider",
+    r"slurp",
+    r"facebookexternalhit",
+    r"embedly",
+    r"bingpreview",
+    r"yandex",
+    // headless browsers and automation
+    r"headlesschrome",
+    r"phantomjs",
+    r"puppeteer",
+    r"playwright",
+    r"selenium",
+    r"webdriver",
+];
+
+// User agents of command line tools and HTTP libraries, which apps and servers
+// use too, so they are only bots with http_clients enabled
+const HTTP_CLIENT_USER_AGENT_PATTERNS: &[&str] = &[
+    r"^curl/",
+    r"^wget/",
+    r"^httpie/",
+    r"python-requests",
+    r"python-urllib",
+    r"aiohttp",
+    r"go-http-client",
+    r"^java/",
+    r"okhttp",
+    r"libwww-perl",
+    r"^ruby",
+    r"apache-httpclient",
+];
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotAction {
+    // add "bot": true to the event metadata
+    #[default]
+    Tag,
+    // don't log bot requests to Moesif
+    Skip,
+    // don't look for bots, block_bot_traffic has no effect
+    Off,
+}
+
+// The `bots` section of the plugin configuration. Bot requests are blocked with a
+// 403 when block_bot_traffic is enabled for the Moesif application, otherwise the
+// action decides what happens to their events.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct BotConfig {
+    #[serde(default)]
+    pub action: BotAction,
+    // also treat command line tools and HTTP libraries as bots
+    #[serde(default)]
+    pub http_clients: bool,
+    // extra regexes matched case insensitively against the user-agent
+    #[serde(default)]
+    pub user_agent_patterns: Vec<String>,
+}
+
+impl BotConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        for pattern in &self.user_agent_patterns {
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(format!(
+                    "bots.user_agent_patterns {:?} is not a valid regex: {}",
+                    pattern, e
+                ));
+            }
+        }
+    }
+}
+
+// BotConfig with the built in and configured patterns compiled into one set
+#[derive(Default, Clone)]
+pub struct BotDetector {
+    action: BotAction,
+    user_agents: Option<RegexSet>,
+}
+
+impl BotDetector {
+    // Invalid patterns are dropped here, they are reported by BotConfig::validate
+    pub fn new(config: &BotConfig) -> BotDetector {
+        if config.action == BotAction::Off {
+            return BotDetector::default();
+        }
+        let http_clients: &[&str] = if config.http_clients {
+            HTTP_CLIENT_USER_AGENT_PATTERNS
+        } else {
+            &[]
+        };
+        let patterns = BOT_USER_AGENT_PATTERNS
+            .iter()
+            .chain(http_clients)
+            .map(|p| p.to_string())
+            .chain(
+                config
+                    .user_agent_patterns
+                    .iter()
+                    .filter(|p| Regex::new(p).is_ok())
+                    .cloned(),
+            )
+            .map(|p| format!("(?i){}", p));
+        BotDetector {
+            action: config.action,
+            user_agents: RegexSet::new(patterns).ok(),
+        }
+    }
+
+    pub fn action(&self) -> BotAction {
+        self.action
+    }
+
+    // Requests without a user-agent are not treated as bots
+    pub fn is_bot(&self, user_agent: Option<&str>) -> bool {
+        match (&self.user_agents, user_agent) {
+            (Some(user_agents), Some(user_agent)) => user_agents.is_match(user_agent),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_built_in_and_configured_bots() {
+        let detector = BotDetector::new(&BotConfig {
+            user_agent_patterns: vec!["^internal-monitor".to_string()],
+            ..Default::default()
+        });
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/120.0.0.0 Safari/537.36",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Internal-Monitor/1.0",
+        ] {
+            assert!(detector.is_bot(Some(user_agent)), "{}", user_agent);
+        }
+        for user_agent in [
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_1) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15",
+            "MyApp/3.2 (iPhone; iOS 17.1)",
+            "Mozilla/5.0 (Linux; Android 10; Cubot X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+            "curl/8.4.0",
+            "okhttp/4.12.0",
+        ] {
+            assert!(!detector.is_bot(Some(user_agent)), "{}", user_agent);
+        }
+        assert!(!detector.is_bot(None));
+
+        let http_clients = BotDetector::new(&BotConfig {
+            http_clients: true,
+            ..Default::default()
+        });
+        for user_agent in ["curl/8.4.0", "python-requests/2.31.0", "Java/17.0.2"] {
+            assert!(http_clients.is_bot(Some(user_agent)), "{}", user_agent);
+        }
+
+        let off = BotDetector::new(&BotConfig {
+            action: BotAction::Off,
+            ..Default::default()
+        });
+        assert!(!off.is_bot(Some("curl/8.4.0")));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::validate_header_name;
+use crate::ip_ranges::{parse_cidr, IpRanges};
+
+fn default_headers() -> Vec<String> {
+    [
+        "x-client-ip",
+        "x-forwarded-for",
+        "cf-connecting-ip",
+        "fastly-client-ip",
+        "true-client-ip",
+        "x-real-ip",
+        "x-cluster-client-ip",
+        "x-forwarded",
+        "forwarded-for",
+        "forwarded",
+        "x-appengine-user-ip",
+        "cf-pseudo-ipv4",
+    ]
+    .iter()
+    .map(|h| h.to_string())
+    .collect()
+}
+
+// Headers proxies append the address of their peer to. Single address headers
+// such as x-client-ip are passed through by most proxies as the client sent them.
+fn appended_headers() -> Vec<String> {
+    vec!["x-forwarded-for".to_string(), "forwarded".to_string()]
+}
+
+// The `client_ip` section of the plugin configuration
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientIpConfig {
+    // headers checked for the client IP in priority order, by default the common
+    // client IP headers, or only x-forwarded-for and forwarded with trusted_proxies
+    #[serde(default)]
+    pub headers: Option<Vec<String>>,
+    // addresses and CIDR ranges of the proxies in front of Envoy
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+impl ClientIpConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        for header in self.headers.iter().flatten() {
+            validate_header_name("client_ip.headers", Some(header), errors);
+        }
+        for proxy in &self.trusted_proxies {
+            if parse_cidr(proxy).is_none() {
+                errors.push(format!(
+                    "client_ip.trusted_proxies {:?} is not an IP address or CIDR range",
+                    proxy
+                ));
+            }
+        }
+    }
+}
+
+// Resolves the client IP of a request. Without trusted proxies the first address in
+// the highest priority header is used. With trusted proxies, headers are only
+// believed when Envoy's peer is a trusted proxy, and their addresses are walked
+// from right to left skipping trusted proxies, so a client can't spoof its address
+// by sending the headers itself.
+#[derive(Default, Clone)]
+pub struct ClientIpResolver {
+    headers: Vec<String>,
+    trusted_proxies: IpRanges,
+}
+
+impl ClientIpResolver {
+    pub fn new(config: &ClientIpConfig) -> ClientIpResolver {
+        let trusted_proxies = if config.trusted_proxies.is_empty() {
+            IpRanges::default()
+        } else {
+            let ranges = vec![(
+                "trusted_proxies".to_string(),
+                config.trusted_proxies.join(","),
+            )];
+            IpRanges::new(&ranges.into_iter().collect())
+        };
+        let headers = match &config.headers {
+            Some(headers) => headers.clone(),
+            None if config.trusted_proxies.is_empty() => default_headers(),
+            None => appended_headers(),
+        };
+        ClientIpResolver {
+            headers: headers.iter().map(|h| h.to_lowercase()).collect(),
+            trusted_proxies,
+        }
+    }
+
+    // The request headers must already be lowercased. source_address is Envoy's
+    // source.address property, the address of the peer connected to Envoy.
+    pub fn resolve<F>(&self, headers: &HashMap<String, String>, source_address: F) -> Option<String>
+    where
+        F: FnOnce() -> Option<String>,
+    {
+        if self.trusted_proxies.is_empty() {
+            return self
+                .header_address(headers)
+                .or_else(|| source_address().and_then(|address| parse_node(&address)))
+                .map(|ip| ip.to_string());
+        }
+        let peer = source_address().and_then(|address| parse_node(&address));
+        if let Some(peer) = peer.filter(|peer| !self.trusted_proxies.contains(*peer)) {
+            return Some(peer.to_string());
+        }
+        self.header_address(headers)
+            .or(peer)
+            .map(|ip| ip.to_string())
+    }
+
+    fn header_address(&self, headers: &HashMap<String, String>) -> Option<IpAddr> {
+        for header in &self.headers {
+            if let Some(value) = headers.get(header) {
+                let addresses = if header == "forwarded" {
+                    parse_forwarded(value)
+                } else {
+                    value.split(',').filter_map(parse_node).collect()
+                };
+                if let Some(ip) = self.client_address(&addresses) {
+                    return Some(ip);
+                }
+            }
+        }
+        None
+    }
+
+    // addresses are in the order they were appended, client first
+    fn client_address(&self, addresses: &[IpAddr]) -> Option<IpAddr> {
+        if self.trusted_proxies.is_empty() {
+            return addresses.first().copied();
+        }
+        addresses
+            .iter()
+            .rev()
+            .find(|ip| !self.trusted_proxies.contains(**ip))
+            .or_else(|| addresses.first())
+            .copied()
+    }
+}
+
+// The for= addresses of an RFC 7239 Forwarded header, obfuscated and unknown nodes are skipped
+fn parse_forwarded(value: &str) -> Vec<IpAddr> {
+    value
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            if key.trim().eq_ignore_ascii_case("for") {
+                parse_node(value)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// Parses "203.0.113.7", "203.0.113.7:4711", "2001:db8::1" or "[2001:db8::1]:4711",
+// optionally quoted
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    let (ip, _port) = node.rsplit_once(':')?;
+    ip.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn client_ip_uses_first_valid_address() {
+        let resolver = ClientIpResolver::new(&ClientIpConfig::default());
+        let headers = headers(&[
+            ("x-forwarded-for", "unknown, 203.0.113.7, 10.0.0.1"),
+            ("x-real-ip", "198.51.100.1"),
+        ]);
+        assert_eq!(
+            resolver.resolve(&headers, || None),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(
+            resolver.resolve(&HashMap::new(), || Some("192.0.2.1:5000".to_string())),
+            Some("192.0.2.1".to_string())
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_are_skipped_from_the_right() {
+        let resolver = ClientIpResolver::new(&ClientIpConfig {
+            headers: Some(vec!["X-Forwarded-For".to_string(), "forwarded".to_string()]),
+            trusted_proxies: vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()],
+        });
+        let proxy = || Some("10.0.0.2:443".to_string());
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7, 10.0.0.1")]);
+        assert_eq!(
+            resolver.resolve(&spoofed, proxy),
+            Some("203.0.113.7".to_string())
+        );
+
+        // a client connecting directly can't choose its address
+        assert_eq!(
+            resolver.resolve(&spoofed, || Some("198.51.100.9:5000".to_string())),
+            Some("198.51.100.9".to_string())
+        );
+
+        let forwarded = headers(&[(
+            "forwarded",
+            r#"for="[2001:db8:cafe::17]:4711", for=198.51.100.3:8080;proto=https, for=_hidden;by=10.0.0.1"#,
+        )]);
+        assert_eq!(
+            resolver.resolve(&forwarded, proxy),
+            Some("198.51.100.3".to_string())
+        );
+        let only_proxies = headers(&[("forwarded", r#"for="[2001:db8::1]", for=10.0.0.9"#)]);
+        assert_eq!(
+            resolver.resolve(&only_proxies, proxy),
+            Some("2001:db8::1".to_string())
+        );
+    }
+
+    #[test]
+    fn single_address_headers_are_ignored_with_trusted_proxies() {
+        let resolver = ClientIpResolver::new(&ClientIpConfig {
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        });
+        let proxy = || Some("10.0.0.2:443".to_string());
+        let spoofed = headers(&[
+            ("x-client-ip", "1.2.3.4"),
+            ("x-forwarded-for", "1.2.3.4, 203.0.113.7"),
+        ]);
+        assert_eq!(
+            resolver.resolve(&spoofed, proxy),
+            Some("203.0.113.7".to_string())
+        );
+        let only_spoofed = headers(&[("x-client-ip", "1.2.3.4")]);
+        assert_eq!(
+            resolver.resolve(&only_spoofed, proxy),
+            Some("10.0.0.2".to_string())
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use regex::{Regex, RegexSet, SetMatches};
+
+use crate::event::Event;
+
+// Regex conditions of governance rules and sampling rules compiled once when they
+// are loaded. Every pattern used with the same path goes into one RegexSet, so an
+// event is matched with a single lookup and scan per path however many rules use it.
+//
+// An entry matches when any of its condition groups does and a group matches when
+// all of its conditions do. An entry without groups always matches.
+#[derive(Default, Debug)]
+pub struct CompiledConditions {
+    paths: Vec<PathSet>,
+    // entry -> group -> condition, None for a pattern that failed to compile
+    entries: Vec<Vec<Vec<Option<ConditionRef>>>>,
+}
+
+#[derive(Debug)]
+struct PathSet {
+    path: String,
+    patterns: Patterns,
+}
+
+// The patterns of a path, matched one by one when they don't fit in a RegexSet
+#[derive(Debug)]
+enum Patterns {
+    Set(RegexSet),
+    Each(Vec<Regex>),
+}
+
+impl Patterns {
+    fn new(path: &str, patterns: &[String]) -> Patterns {
+        match RegexSet::new(patterns) {
+            Ok(set) => Patterns::Set(set),
+            Err(e) => {
+                log::warn!(
+                    "Matching the {} patterns of {} one by one: {}",
+                    patterns.len(),
+                    path,
+                    e
+                );
+                // every pattern compiled on its own in compile
+                Patterns::Each(patterns.iter().filter_map(|p| Regex::new(p).ok()).collect())
+            }
+        }
+    }
+
+    fn matches(&self, value: &str) -> PathMatches {
+        match self {
+            Patterns::Set(set) => PathMatches::Set(set.matches(value)),
+            Patterns::Each(regexes) => {
+                PathMatches::Each(regexes.iter().map(|r| r.is_match(value)).collect())
+            }
+        }
+    }
+}
+
+enum PathMatches {
+    Set(SetMatches),
+    Each(Vec<bool>),
+}
+
+impl PathMatches {
+    fn matched(&self, pattern: usize) -> bool {
+        match self {
+            PathMatches::Set(matches) => matches.matched(pattern),
+            PathMatches::Each(matches) => matches.get(pattern).copied().unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ConditionRef {
+    path: usize,
+    pattern: usize,
+}
+
+// (path, regex) pairs of one condition group
+pub type ConditionGroup<'a> = Vec<(&'a str, &'a str)>;
+
+impl CompiledConditions {
+    // Invalid patterns are logged once here with the id of their entry and never match
+    pub fn compile(entries: Vec<(String, Vec<ConditionGroup>)>) -> CompiledConditions {
+        let mut patterns: Vec<(String, Vec<String>)> = Vec::new();
+        let mut compiled_entries = Vec::with_capacity(entries.len());
+        for (id, groups) in entries {
+            let mut compiled_groups = Vec::with_capacity(groups.len());
+            for group in groups {
+                let mut compiled_group = Vec::with_capacity(group.len());
+                for (path, pattern) in group {
+                    if let Err(e) = Regex::new(pattern) {
+                        log::error!(
+                            "Invalid regex in rule {}: path={} regex={}: {}",
+                            id,
+                            path,
+                            pattern,
+                            e
+                        );
+                        compiled_group.push(None);
+                        continue;
+                    }
+                    let path_index = match patterns.iter().position(|(p, _)| p == path) {
+                        Some(i) => i,
+                        None => {
+                            patterns.push((path.to_string(), Vec::new()));
+                            patterns.len() - 1
+                        }
+                    };
+                    let path_patterns = &mut patterns[path_index].1;
+                    let pattern_index = match path_patterns.iter().position(|p| p == pattern) {
+                        Some(i) => i,
+                        None => {
+                            path_patterns.push(pattern.to_string());
+                            path_patterns.len() - 1
+                        }
+                    };
+                    compiled_group.push(Some(ConditionRef {
+                        path: path_index,
+                        pattern: pattern_index,
+                    }));
+                }
+                compiled_groups.push(compiled_group);
+            }
+            compiled_entries.push(compiled_groups);
+        }
+
+        let paths = patterns
+            .into_iter()
+            .map(|(path, patterns)| PathSet {
+                patterns: Patterns::new(&path, &patterns),
+                path,
+            })
+            .collect();
+        CompiledConditions {
+            paths,
+            entries: compiled_entries,
+        }
+    }
+
+    // Whether each entry matches the event, in the order they were compiled
+    pub fn matches(&self, event: &Event) -> Vec<bool> {
+        let path_matches: Vec<Option<PathMatches>> = self
+            .paths
+            .iter()
+            .map(|p| {
+                event
+                    .lookup(&p.path)
+                    .map(|value| p.patterns.matches(&value))
+            })
+            .collect();
+        self.entries
+            .iter()
+            .map(|groups| {
+                groups.is_empty()
+                    || groups.iter().any(|group| {
+                        group.iter().all(|condition| match condition {
+                            Some(c) => path_matches[c.path]
+                                .as_ref()
+                                .is_some_and(|m| m.matched(c.pattern)),
+                            None => false,
+                        })
+                    })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::RequestInfo;
+
+    fn event(verb: &str, uri: &str) -> Event {
+        Event {
+            request: RequestInfo {
+                verb: verb.to_string(),
+                uri: uri.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn groups_are_ored_and_conditions_anded() {
+        let conditions = CompiledConditions::compile(vec![
+            (
+                "orders".to_string(),
+                vec![
+                    vec![("request.verb", "POST"), ("request.route", "^/orders")],
+                    vec![("request.route", "^/admin")],
+                ],
+            ),
+            ("all".to_string(), vec![]),
+            ("broken".to_string(), vec![vec![("request.route", "(")]]),
+            ("user".to_string(), vec![vec![("user_id", ".*")]]),
+        ]);
+        assert_eq!(conditions.paths.len(), 3);
+        assert_eq!(
+            conditions.matches(&event("POST", "/orders/1")),
+            vec![true, true, false, false]
+        );
+        assert_eq!(
+            conditions.matches(&event("GET", "/admin")),
+            vec![true, true, false, false]
+        );
+        assert_eq!(
+            conditions.matches(&event("GET", "/orders/1")),
+            vec![false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn patterns_too_large_for_a_set_are_matched_one_by_one() {
+        // each pattern fits the size limit, all of them together don't
+        let patterns: Vec<String> = (0..2).map(|i| format!(r"^/\w{{150}}-{}$", i)).collect();
+        let entries = patterns
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i.to_string(), vec![vec![("request.route", p.as_str())]]))
+            .collect();
+        let conditions = CompiledConditions::compile(entries);
+        assert!(matches!(conditions.paths[0].patterns, Patterns::Each(_)));
+        let uri = format!("/{}-1", "a".repeat(150));
+        assert_eq!(conditions.matches(&event("GET", &uri)), vec![false, true]);
+    }
+}
@@ -1,15 +1,40 @@
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+use crate::actions::{validate_actions, ActionConfig, ActionMatcher};
+use crate::bots::{BotConfig, BotDetector};
+use crate::client_ip::{ClientIpConfig, ClientIpResolver};
+use crate::conditions::CompiledConditions;
+use crate::event::Event;
+use crate::identity::{IdentityConfig, IdentityResolver};
+use crate::ip_ranges::IpRanges;
+use crate::metadata::MetadataConfig;
+use crate::profiles::ProfileConfig;
+use crate::quota::QuotaConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::skip::{SkipConfig, SkipRules};
 
 #[derive(Default, Clone)]
 pub struct Config {
     pub env: EnvConfig,
     pub event_queue_id: u32,
+    pub profile_queue_id: u32,
+    pub action_queue_id: u32,
+    pub skip: SkipRules,
+    pub bots: BotDetector,
+    pub client_ip: ClientIpResolver,
+    pub identity: IdentityResolver,
+    pub actions: ActionMatcher,
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct EnvConfig {
+    #[serde(default)]
     pub moesif_application_id: String,
+    pub moesif_application_id_env: Option<String>,
     pub user_id_header: Option<String>,
     pub company_id_header: Option<String>,
     #[serde(default = "default_batch_max_size")]
@@ -20,10 +45,43 @@ pub struct EnvConfig {
     pub upstream: String,
     #[serde(default = "default_base_uri")]
     pub base_uri: String,
+    // prefix of the default Moesif API paths, such as /moesif for an egress gateway
+    #[serde(default)]
+    pub base_path: String,
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+    // static headers added to every call to the upstream
+    #[serde(default)]
+    pub upstream_headers: HashMap<String, String>,
     #[serde(default = "default_debug")]
     pub debug: bool,
     #[serde(default = "connection_timeout")]
     pub connection_timeout: usize,
+    #[serde(default = "default_log_body")]
+    pub log_body: bool,
+    #[serde(default)]
+    pub override_response_status: bool,
+    #[serde(default)]
+    pub skip: SkipConfig,
+    #[serde(default)]
+    pub bots: BotConfig,
+    #[serde(default)]
+    pub client_ip: ClientIpConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    // dynamic metadata namespaces added to the event metadata
+    #[serde(default)]
+    pub dynamic_metadata: Vec<String>,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[serde(default)]
+    pub profiles: ProfileConfig,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
 }
 
 fn default_batch_max_size() -> usize {
@@ -50,6 +108,339 @@ fn connection_timeout() -> usize {
     5000
 }
 
+fn default_log_body() -> bool {
+    true
+}
+
+// Headers of the calls to the upstream that upstream_headers can't replace
+const RESERVED_UPSTREAM_HEADERS: [&str; 5] = [
+    "accept",
+    "content-type",
+    "content-length",
+    "host",
+    "x-moesif-application-id",
+];
+
+// The Moesif API endpoints the plugin calls
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    Events,
+    Actions,
+    Users,
+    Companies,
+    Config,
+    Rules,
+}
+
+// The `endpoints` section of the plugin configuration, full paths that replace the
+// default path of an endpoint. base_path isn't added to them.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct EndpointsConfig {
+    pub events: Option<String>,
+    pub actions: Option<String>,
+    pub users: Option<String>,
+    pub companies: Option<String>,
+    pub config: Option<String>,
+    pub rules: Option<String>,
+}
+
+impl EndpointsConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        let paths = [
+            ("events", &self.events),
+            ("actions", &self.actions),
+            ("users", &self.users),
+            ("companies", &self.companies),
+            ("config", &self.config),
+            ("rules", &self.rules),
+        ];
+        for (name, path) in paths.iter() {
+            if let Some(path) = path {
+                if !path.starts_with('/') {
+                    errors.push(format!("endpoints.{} {:?} must start with /", name, path));
+                }
+            }
+        }
+    }
+
+    fn get(&self, endpoint: Endpoint) -> Option<&String> {
+        match endpoint {
+            Endpoint::Events => self.events.as_ref(),
+            Endpoint::Actions => self.actions.as_ref(),
+            Endpoint::Users => self.users.as_ref(),
+            Endpoint::Companies => self.companies.as_ref(),
+            Endpoint::Config => self.config.as_ref(),
+            Endpoint::Rules => self.rules.as_ref(),
+        }
+    }
+}
+
+impl Endpoint {
+    fn default_path(self) -> &'static str {
+        match self {
+            Endpoint::Events => "/v1/events/batch",
+            Endpoint::Actions => "/v1/actions/batch",
+            Endpoint::Users => "/v1/users/batch",
+            Endpoint::Companies => "/v1/companies/batch",
+            Endpoint::Config => "/v1/config",
+            Endpoint::Rules => "/v1/rules",
+        }
+    }
+}
+
+const MAX_BATCH_MAX_WAIT: usize = 300_000;
+const MAX_CONNECTION_TIMEOUT: usize = 60_000;
+
+impl EnvConfig {
+    // Parse the raw plugin configuration bytes, which may be JSON or YAML, resolve
+    // ${NAME} references in string values with `lookup` and validate the result.
+    // Every problem found is returned so they can all be reported at once.
+    pub fn from_bytes<F>(config_bytes: &[u8], lookup: F) -> Result<EnvConfig, Vec<String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let config_str = std::str::from_utf8(config_bytes)
+            .map_err(|e| vec![format!("configuration is not valid UTF-8: {}", e)])?;
+        let mut value = parse_config_value(config_str).map_err(|e| vec![e])?;
+
+        let mut errors = Vec::new();
+        substitute_variables(&mut value, &lookup, &mut errors);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut env: EnvConfig = serde_ignored::deserialize(value, |path| {
+            log::warn!("Ignoring unknown configuration field: {}", path);
+        })
+        .map_err(|e| vec![format!("configuration is not valid: {}", e)])?;
+        env.resolve_application_id(&lookup)?;
+        env.validate()?;
+        Ok(env)
+    }
+
+    // The path of a Moesif API endpoint, its override or the default under base_path
+    pub fn endpoint_path(&self, endpoint: Endpoint) -> String {
+        match self.endpoints.get(endpoint) {
+            Some(path) => path.clone(),
+            None => format!("{}{}", self.base_path.trim_end_matches('/'), endpoint.default_path()),
+        }
+    }
+
+    fn resolve_application_id<F>(&mut self, lookup: &F) -> Result<(), Vec<String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let name = match &self.moesif_application_id_env {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        if !self.moesif_application_id.is_empty() {
+            return Err(vec![
+                "set only one of moesif_application_id and moesif_application_id_env".to_string(),
+            ]);
+        }
+        match lookup(name) {
+            Some(application_id) => {
+                self.moesif_application_id = application_id;
+                Ok(())
+            }
+            None => Err(vec![format!(
+                "moesif_application_id_env names variable {:?} which is not set",
+                name
+            )]),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.moesif_application_id.trim().is_empty() {
+            errors.push(
+                "moesif_application_id or moesif_application_id_env is required".to_string(),
+            );
+        }
+        if self.batch_max_size == 0 {
+            errors.push("batch_max_size must be greater than 0".to_string());
+        }
+        if self.batch_max_wait == 0 || self.batch_max_wait > MAX_BATCH_MAX_WAIT {
+            errors.push(format!(
+                "batch_max_wait must be between 1 and {} milliseconds, got {}",
+                MAX_BATCH_MAX_WAIT, self.batch_max_wait
+            ));
+        }
+        if self.connection_timeout == 0 || self.connection_timeout > MAX_CONNECTION_TIMEOUT {
+            errors.push(format!(
+                "connection_timeout must be between 1 and {} milliseconds, got {}",
+                MAX_CONNECTION_TIMEOUT, self.connection_timeout
+            ));
+        }
+        if self.upstream.trim().is_empty() {
+            errors.push("upstream must not be empty".to_string());
+        }
+        if self.base_uri.trim().is_empty() {
+            errors.push("base_uri must not be empty".to_string());
+        }
+        if !self.base_path.is_empty() && !self.base_path.starts_with('/') {
+            errors.push(format!("base_path {:?} must start with /", self.base_path));
+        }
+        self.endpoints.validate(&mut errors);
+        for (name, value) in &self.upstream_headers {
+            validate_header_name("upstream_headers", Some(name), &mut errors);
+            if RESERVED_UPSTREAM_HEADERS.contains(&name.to_lowercase().as_str()) {
+                errors.push(format!("upstream_headers {:?} is set by the plugin", name));
+            }
+            if value.contains(['\r', '\n']) {
+                errors.push(format!("upstream_headers.{} must not contain line breaks", name));
+            }
+        }
+        validate_header_name("user_id_header", self.user_id_header.as_deref(), &mut errors);
+        validate_header_name("company_id_header", self.company_id_header.as_deref(), &mut errors);
+        self.skip.validate(&mut errors);
+        self.bots.validate(&mut errors);
+        self.client_ip.validate(&mut errors);
+        self.quota.validate(&mut errors);
+        self.rate_limits.validate(&mut errors);
+        self.metadata.validate("metadata", &mut errors);
+        if self.dynamic_metadata.iter().any(|namespace| namespace.is_empty()) {
+            errors.push("dynamic_metadata namespaces must not be empty".to_string());
+        }
+        self.identity.validate(&mut errors);
+        self.profiles.validate(&mut errors);
+        validate_actions(&self.actions, &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Settings a route can override with `filter_metadata` under the `moesif` key
+// of its route metadata, so one plugin instance can apply different policies per API.
+#[derive(Default, Clone, Debug)]
+pub struct RouteOverrides {
+    pub skip: Option<bool>,
+    pub sample_rate: Option<i32>,
+    pub user_id_header: Option<String>,
+    pub company_id_header: Option<String>,
+    pub log_body: Option<bool>,
+    // a name in rate_limits.routes
+    pub rate_limit: Option<String>,
+}
+
+impl RouteOverrides {
+    // `get` returns the Envoy serialized value of a single metadata field by name.
+    // Struct fields arrive as raw bytes for strings, one byte for bools and
+    // a little endian f64 for numbers.
+    pub fn from_metadata<F>(get: F) -> RouteOverrides
+    where
+        F: Fn(&str) -> Option<Vec<u8>>,
+    {
+        let sample_rate = get("sample_rate").and_then(|v| metadata_number(&v));
+        RouteOverrides {
+            skip: get("skip").and_then(|v| metadata_bool(&v)),
+            sample_rate: sample_rate.map(|rate| rate.clamp(0.0, 100.0) as i32),
+            user_id_header: get("user_id_header").and_then(metadata_string),
+            company_id_header: get("company_id_header").and_then(metadata_string),
+            log_body: get("log_body").and_then(|v| metadata_bool(&v)),
+            rate_limit: get("rate_limit").and_then(metadata_string),
+        }
+    }
+}
+
+fn metadata_bool(value: &[u8]) -> Option<bool> {
+    match value {
+        [b] => Some(*b != 0),
+        _ => std::str::from_utf8(value).ok()?.parse().ok(),
+    }
+}
+
+fn metadata_number(value: &[u8]) -> Option<f64> {
+    match <[u8; 8]>::try_from(value) {
+        Ok(bytes) => Some(f64::from_le_bytes(bytes)),
+        Err(_) => std::str::from_utf8(value).ok()?.parse().ok(),
+    }
+}
+
+fn metadata_string(value: Vec<u8>) -> Option<String> {
+    String::from_utf8(value).ok().filter(|s| !s.is_empty())
+}
+
+// A configuration starting with '{' is JSON, which gives better error positions,
+// anything else is read as YAML.
+fn parse_config_value(config_str: &str) -> Result<serde_json::Value, String> {
+    if config_str.trim_start().starts_with('{') {
+        serde_json::from_str(config_str)
+            .map_err(|e| format!("configuration is not valid JSON: {}", e))
+    } else {
+        serde_yaml::from_str(config_str)
+            .map_err(|e| format!("configuration is not valid YAML: {}", e))
+    }
+}
+
+// ${NAME}, or $${NAME} for a literal ${NAME}
+fn variable_regex() -> &'static Regex {
+    static VARIABLE: OnceLock<Regex> = OnceLock::new();
+    VARIABLE.get_or_init(|| Regex::new(r"\$(\$?)\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap())
+}
+
+// Replace ${NAME} in every string value, recording each variable that can't be resolved.
+// Values stay strings, so fields such as batch_max_size can't be set from a variable.
+fn substitute_variables<F>(value: &mut serde_json::Value, lookup: &F, errors: &mut Vec<String>)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        serde_json::Value::String(s) => {
+            let substituted = variable_regex().replace_all(s, |caps: &Captures| {
+                if !caps[1].is_empty() {
+                    return format!("${{{}}}", &caps[2]);
+                }
+                lookup(&caps[2]).unwrap_or_else(|| {
+                    errors.push(format!(
+                        "configuration references variable {:?} which is not set",
+                        &caps[2]
+                    ));
+                    String::new()
+                })
+            });
+            *s = substituted.into_owned();
+        }
+        serde_json::Value::Array(values) => {
+            for v in values {
+                substitute_variables(v, lookup, errors);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for v in map.values_mut() {
+                substitute_variables(v, lookup, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Envoy stores header names lowercased, so a configured name must be a valid
+// HTTP token that still matches after ASCII lowercasing.
+pub(crate) fn validate_header_name(field: &str, name: Option<&str>, errors: &mut Vec<String>) {
+    let name = match name {
+        Some(name) => name,
+        None => return,
+    };
+    if name.is_empty() {
+        errors.push(format!("{} must not be empty", field));
+    } else if let Some(c) = name.chars().find(|c| !is_header_token_char(*c)) {
+        errors.push(format!(
+            "{} {:?} is not a valid header name, invalid character {:?}",
+            field, name, c
+        ));
+    }
+}
+
+fn is_header_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
 #[derive(Default, Serialize, Deserialize, Debug)]
 pub struct AppConfigResponse {
     pub org_id: String,
@@ -64,6 +455,10 @@ pub struct AppConfigResponse {
     pub regex_config: Vec<RegexRule>,
     pub billing_config_jsons: HashMap<String, String>,
     pub e_tag: Option<String>,
+    #[serde(skip)]
+    pub regex_conditions: CompiledConditions,
+    #[serde(skip)]
+    pub ip_blocklist: IpRanges,
 }
 
 impl AppConfigResponse {
@@ -74,14 +469,37 @@ impl AppConfigResponse {
         }
     }
 
-    pub fn get_sampling_percentage(&self, user_id: Option<&str>, company_id: Option<&str>) -> i32 {
-        if let Some(user_id) = user_id {
+    // Parses a /v1/config response and compiles its regex_config and IP blocklist
+    pub fn from_slice(body: &[u8]) -> Result<AppConfigResponse, serde_json::Error> {
+        let mut app_config = serde_json::from_slice::<AppConfigResponse>(body)?;
+        let entries = app_config
+            .regex_config
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let group = rule.conditions.iter().map(|c| (c.path.as_str(), c.value.as_str())).collect();
+                (format!("regex_config[{}]", i), vec![group])
+            })
+            .collect();
+        app_config.regex_conditions = CompiledConditions::compile(entries);
+        app_config.ip_blocklist = IpRanges::new(&app_config.ip_addresses_blocked_by_name);
+        Ok(app_config)
+    }
+
+    // The first regex_config rule whose conditions all match wins, then the user
+    // and company rates, then the application's default rate
+    pub fn get_sampling_percentage(&self, event: &Event) -> i32 {
+        if let Some(i) = self.regex_conditions.matches(event).iter().position(|m| *m) {
+            return self.regex_config[i].sample_rate;
+        }
+
+        if let Some(user_id) = &event.user_id {
             if let Some(user_rate) = self.user_sample_rate.get(user_id) {
                 return *user_rate;
             }
         }
 
-        if let Some(company_id) = company_id {
+        if let Some(company_id) = &event.company_id {
             if let Some(company_rate) = self.company_sample_rate.get(company_id) {
                 return *company_rate;
             }
@@ -108,3 +526,98 @@ pub struct RegexCondition {
     pub path: String,
     pub value: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_variables(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn parses_json_with_defaults() {
+        let env = EnvConfig::from_bytes(br#"{"moesif_application_id": "app"}"#, no_variables).unwrap();
+        assert_eq!(env.moesif_application_id, "app");
+        assert_eq!(env.batch_max_size, 100);
+        assert_eq!(env.batch_max_wait, 2000);
+        assert_eq!(env.upstream, "moesif_api");
+        assert!(env.log_body);
+    }
+
+    #[test]
+    fn parses_yaml_and_substitutes_variables() {
+        let lookup = |name: &str| match name {
+            "APP_ID" => Some("app".to_string()),
+            "CLUSTER" => Some("moesif".to_string()),
+            _ => None,
+        };
+        let config = b"moesif_application_id_env: APP_ID\nupstream: ${CLUSTER}_api\nupstream_headers:\n  x-template: $${CLUSTER}\n";
+        let env = EnvConfig::from_bytes(config, lookup).unwrap();
+        assert_eq!(env.moesif_application_id, "app");
+        assert_eq!(env.upstream, "moesif_api");
+        assert_eq!(env.upstream_headers["x-template"], "${CLUSTER}");
+    }
+
+    #[test]
+    fn reports_every_invalid_value() {
+        let config = br#"{
+            "moesif_application_id": " ",
+            "batch_max_size": 0,
+            "batch_max_wait": 0,
+            "connection_timeout": 600000,
+            "user_id_header": "X User"
+        }"#;
+        let errors = EnvConfig::from_bytes(config, no_variables).unwrap_err();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(errors[4].contains("user_id_header"));
+    }
+
+    #[test]
+    fn resolves_endpoint_paths_and_checks_upstream_headers() {
+        let config = br#"{
+            "moesif_application_id": "app",
+            "base_path": "/moesif/",
+            "endpoints": {"events": "/collector/events"},
+            "upstream_headers": {"x-egress-token": "secret"}
+        }"#;
+        let env = EnvConfig::from_bytes(config, no_variables).unwrap();
+        assert_eq!(env.endpoint_path(Endpoint::Events), "/collector/events");
+        assert_eq!(env.endpoint_path(Endpoint::Config), "/moesif/v1/config");
+        let env = EnvConfig::from_bytes(br#"{"moesif_application_id": "app"}"#, no_variables).unwrap();
+        assert_eq!(env.endpoint_path(Endpoint::Rules), "/v1/rules");
+
+        let config = br#"{
+            "moesif_application_id": "app",
+            "base_path": "moesif",
+            "endpoints": {"users": "v1/users"},
+            "upstream_headers": {"Content-Length": "1", "x-a": "b\r\nx-c: d"}
+        }"#;
+        let errors = EnvConfig::from_bytes(config, no_variables).unwrap_err();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+    }
+
+    #[test]
+    fn rejects_bad_bytes_and_missing_variables() {
+        let errors = EnvConfig::from_bytes(&[0xff, 0xfe], no_variables).unwrap_err();
+        assert!(errors[0].contains("UTF-8"));
+        let errors =
+            EnvConfig::from_bytes(br#"{"moesif_application_id": "${APP_ID}"}"#, no_variables)
+                .unwrap_err();
+        assert_eq!(errors, vec![r#"configuration references variable "APP_ID" which is not set"#]);
+    }
+
+    #[test]
+    fn reads_route_overrides_from_metadata() {
+        let route = RouteOverrides::from_metadata(|name| match name {
+            "skip" => Some(vec![0]),
+            "sample_rate" => Some(25.0f64.to_le_bytes().to_vec()),
+            "user_id_header" => Some(b"x-route-user".to_vec()),
+            _ => None,
+        });
+        assert_eq!(route.skip, Some(false));
+        assert_eq!(route.sample_rate, Some(25));
+        assert_eq!(route.user_id_header.as_deref(), Some("x-route-user"));
+        assert_eq!(route.log_body, None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::convert::TryFrom;
+
+use serde_json::{Map, Value};
+
+use crate::host::Host;
+
+// protobuf wire types
+const VARINT: u8 = 0;
+const I64: u8 = 1;
+const LEN: u8 = 2;
+const I32: u8 = 5;
+
+// Reads the dynamic metadata other filters wrote under a namespace such as
+// envoy.filters.http.jwt_authn, None when the namespace isn't set
+pub fn read_namespace(host: &dyn Host, namespace: &str) -> Option<Value> {
+    let bytes = host.get_property(vec!["metadata", "filter_metadata", namespace])?;
+    let value = decode_struct(&bytes);
+    if value.is_none() {
+        log::warn!("Failed to decode dynamic metadata {}", namespace);
+    }
+    value
+}
+
+// Envoy returns a namespace as a serialized google.protobuf.Struct, decoded here
+// into the JSON value it represents. Unknown fields are skipped.
+pub fn decode_struct(bytes: &[u8]) -> Option<Value> {
+    let mut fields = Map::new();
+    let mut reader = Reader::new(bytes);
+    while let Some((field, wire_type)) = reader.field()? {
+        if field != 1 || wire_type != LEN {
+            reader.skip(wire_type)?;
+            continue;
+        }
+        // map<string, Value> entries
+        let mut entry = Reader::new(reader.len_delimited()?);
+        let mut key = String::new();
+        let mut value = Value::Null;
+        while let Some((field, wire_type)) = entry.field()? {
+            match (field, wire_type) {
+                (1, LEN) => key = String::from_utf8(entry.len_delimited()?.to_vec()).ok()?,
+                (2, LEN) => value = decode_value(entry.len_delimited()?)?,
+                _ => entry.skip(wire_type)?,
+            }
+        }
+        fields.insert(key, value);
+    }
+    Some(Value::Object(fields))
+}
+
+fn decode_value(bytes: &[u8]) -> Option<Value> {
+    let mut value = Value::Null;
+    let mut reader = Reader::new(bytes);
+    while let Some((field, wire_type)) = reader.field()? {
+        value = match (field, wire_type) {
+            (1, VARINT) => {
+                reader.varint()?;
+                Value::Null
+            }
+            (2, I64) => {
+                let number = f64::from_le_bytes(reader.fixed64()?);
+                serde_json::Number::from_f64(number).map_or(Value::Null, Value::Number)
+            }
+            (3, LEN) => Value::String(String::from_utf8(reader.len_delimited()?.to_vec()).ok()?),
+            (4, VARINT) => Value::Bool(reader.varint()? != 0),
+            (5, LEN) => decode_struct(reader.len_delimited()?)?,
+            (6, LEN) => {
+                let mut items = Vec::new();
+                let mut list = Reader::new(reader.len_delimited()?);
+                while let Some((field, wire_type)) = list.field()? {
+                    if field == 1 && wire_type == LEN {
+                        items.push(decode_value(list.len_delimited()?)?);
+                    } else {
+                        list.skip(wire_type)?;
+                    }
+                }
+                Value::Array(items)
+            }
+            _ => {
+                reader.skip(wire_type)?;
+                continue;
+            }
+        };
+    }
+    Some(value)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+// Every method returns None for truncated or malformed input
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    // The next field number and wire type, Some(None) at the end of the message
+    fn field(&mut self) -> Option<Option<(u64, u8)>> {
+        if self.bytes.is_empty() {
+            return Some(None);
+        }
+        let tag = self.varint()?;
+        Some(Some((tag >> 3, (tag & 7) as u8)))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for (i, byte) in self.bytes.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.bytes = &self.bytes[i + 1..];
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn fixed64(&mut self) -> Option<[u8; 8]> {
+        <[u8; 8]>::try_from(self.take(8)?).ok()
+    }
+
+    fn len_delimited(&mut self) -> Option<&'a [u8]> {
+        let len = usize::try_from(self.varint()?).ok()?;
+        self.take(len)
+    }
+
+    fn skip(&mut self, wire_type: u8) -> Option<()> {
+        match wire_type {
+            VARINT => self.varint().map(|_| ()),
+            I64 => self.take(8).map(|_| ()),
+            LEN => self.len_delimited().map(|_| ()),
+            I32 => self.take(4).map(|_| ()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn len_field(field: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![field << 3 | LEN, bytes.len() as u8];
+        encoded.extend_from_slice(bytes);
+        encoded
+    }
+
+    fn entry(key: &str, value: Vec<u8>) -> Vec<u8> {
+        let mut entry = len_field(1, key.as_bytes());
+        entry.extend(len_field(2, &value));
+        len_field(1, &entry)
+    }
+
+    #[test]
+    fn decodes_struct_values() {
+        let mut number = vec![2 << 3 | I64];
+        number.extend_from_slice(&1.5f64.to_le_bytes());
+        let nested = entry("admin", vec![4 << 3, 1]);
+        let list = [
+            len_field(1, &len_field(3, b"a")),
+            len_field(1, &len_field(3, b"b")),
+        ]
+        .concat();
+        let bytes = [
+            entry("sub", len_field(3, b"user-1")),
+            entry("exp", number),
+            entry("claims", len_field(5, &nested)),
+            entry("roles", len_field(6, &list)),
+            entry("none", vec![1 << 3, 0]),
+            // an unknown field
+            vec![9 << 3 | I32, 1, 2, 3, 4],
+        ]
+        .concat();
+        assert_eq!(
+            decode_struct(&bytes),
+            Some(json!({
+                "sub": "user-1",
+                "exp": 1.5,
+                "claims": {"admin": true},
+                "roles": ["a", "b"],
+                "none": null
+            }))
+        );
+        assert_eq!(decode_struct(&bytes[..bytes.len() - 2]), None);
+    }
+}
@@ -34,4 +34,115 @@ pub struct Event {
     pub direction: String,
     pub session_token: Option<String>,
     pub blocked_by: Option<String>,
+    pub weight: Option<i32>,
+}
+
+impl Event {
+    // Sets a top level metadata field, keeping any other fields already set
+    pub fn set_metadata(&mut self, key: &str, value: serde_json::Value) {
+        if !self.metadata.is_object() {
+            self.metadata = serde_json::Value::Object(serde_json::Map::new());
+        }
+        if let Some(metadata) = self.metadata.as_object_mut() {
+            metadata.insert(key.to_string(), value);
+        }
+    }
+
+    // Resolves a Moesif regex_config path such as request.route, request.headers.<name>,
+    // request.body.<json path> or metadata.<json path> to the string it is matched against.
+    // None means the value is not present on this event.
+    pub fn lookup(&self, path: &str) -> Option<String> {
+        match path {
+            "request.uri" => return Some(self.request.uri.clone()),
+            "request.route" => {
+                let route = self.request.uri.split('?').next().unwrap_or_default();
+                return Some(route.to_string());
+            }
+            "request.verb" => return Some(self.request.verb.clone()),
+            "request.ip_address" => return self.request.ip_address.clone(),
+            "response.status" => return self.response.as_ref().map(|r| r.status.to_string()),
+            "user_id" => return self.user_id.clone(),
+            "company_id" => return self.company_id.clone(),
+            _ => {}
+        }
+        if let Some(name) = path.strip_prefix("request.headers.") {
+            return self.request.headers.get(&name.to_lowercase()).cloned();
+        }
+        if let Some(name) = path.strip_prefix("response.headers.") {
+            let response = self.response.as_ref()?;
+            return response.headers.get(&name.to_lowercase()).cloned();
+        }
+        if let Some(json_path) = path.strip_prefix("request.body.") {
+            return json_lookup(&self.request.body, json_path);
+        }
+        if let Some(json_path) = path.strip_prefix("response.body.") {
+            return json_lookup(&self.response.as_ref()?.body, json_path);
+        }
+        if let Some(json_path) = path.strip_prefix("metadata.") {
+            return json_lookup(&self.metadata, json_path);
+        }
+        None
+    }
+}
+
+// Follows a dotted path through objects, numeric segments index arrays
+pub(crate) fn json_value<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let mut current = value;
+    for segment in path.split('.') {
+        current = match current {
+            serde_json::Value::Object(map) => map.get(segment)?,
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+// json_value as the string a regex is matched against
+pub(crate) fn json_lookup(value: &serde_json::Value, path: &str) -> Option<String> {
+    match json_value(value, path)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn lookup_resolves_regex_config_paths() {
+        let event = Event {
+            request: RequestInfo {
+                verb: "POST".to_string(),
+                uri: "/orders/1?expand=items".to_string(),
+                headers: vec![("x-plan".to_string(), "gold".to_string())].into_iter().collect(),
+                ip_address: Some("203.0.113.7".to_string()),
+                body: json!({"order": {"items": [{"sku": "A1"}], "total": 12.5}}),
+                ..Default::default()
+            },
+            response: Some(ResponseInfo {
+                status: 201,
+                ..Default::default()
+            }),
+            user_id: Some("user-1".to_string()),
+            metadata: json!({"tier": {"name": "free"}}),
+            ..Default::default()
+        };
+        let lookup = |path| event.lookup(path);
+        assert_eq!(lookup("request.route").as_deref(), Some("/orders/1"));
+        assert_eq!(lookup("request.uri").as_deref(), Some("/orders/1?expand=items"));
+        assert_eq!(lookup("request.ip_address").as_deref(), Some("203.0.113.7"));
+        assert_eq!(lookup("request.headers.X-Plan").as_deref(), Some("gold"));
+        assert_eq!(lookup("request.body.order.items.0.sku").as_deref(), Some("A1"));
+        assert_eq!(lookup("request.body.order.total").as_deref(), Some("12.5"));
+        assert_eq!(lookup("response.status").as_deref(), Some("201"));
+        assert_eq!(lookup("user_id").as_deref(), Some("user-1"));
+        assert_eq!(lookup("metadata.tier.name").as_deref(), Some("free"));
+        assert_eq!(lookup("company_id"), None);
+        assert_eq!(lookup("request.body.order.missing"), None);
+        assert_eq!(lookup("request.unknown"), None);
+    }
 }
@@ -0,0 +1,263 @@
+use std::time::{Duration, SystemTime};
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, Bytes, MapType, Status};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Host is every proxy-wasm host call the plugin makes. The contexts call the host
+// through this trait rather than the proxy-wasm trait defaults so the whole
+// request lifecycle can run against a MockHost in tests on any target.
+pub trait Host {
+    fn get_current_time(&self) -> SystemTime;
+
+    fn set_tick_period(&self, period: Duration);
+
+    fn get_property(&self, path: Vec<&str>) -> Option<Bytes>;
+
+    fn get_buffer(&self, buffer_type: BufferType, start: usize, max_size: usize) -> Option<Bytes>;
+
+    fn get_map(&self, map_type: MapType) -> Vec<(String, String)>;
+
+    fn get_map_value(&self, map_type: MapType, key: &str) -> Option<String>;
+
+    fn set_map_value(&self, map_type: MapType, key: &str, value: Option<&str>);
+
+    fn register_shared_queue(&self, name: &str) -> u32;
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status>;
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status>;
+
+    // Shared data is visible to every worker. The returned cas is passed back to
+    // set_shared_data, which fails with CasMismatch if another worker wrote first.
+    fn get_shared_data(&self, key: &str) -> (Option<Bytes>, Option<u32>);
+
+    fn set_shared_data(&self, key: &str, value: Option<&[u8]>, cas: Option<u32>) -> Result<(), Status>;
+
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status>;
+
+    fn send_http_response(&self, status_code: u32, headers: Vec<(&str, &str)>, body: Option<&[u8]>);
+
+    // The helpers below mirror the proxy-wasm context methods of the same name
+
+    fn get_vm_configuration(&self) -> Option<Bytes> {
+        self.get_buffer(BufferType::VmConfiguration, 0, usize::MAX)
+    }
+
+    fn get_plugin_configuration(&self) -> Option<Bytes> {
+        self.get_buffer(BufferType::PluginConfiguration, 0, usize::MAX)
+    }
+
+    fn get_http_request_headers(&self) -> Vec<(String, String)> {
+        self.get_map(MapType::HttpRequestHeaders)
+    }
+
+    fn get_http_request_header(&self, name: &str) -> Option<String> {
+        self.get_map_value(MapType::HttpRequestHeaders, name)
+    }
+
+    fn get_http_request_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        self.get_buffer(BufferType::HttpRequestBody, start, max_size)
+    }
+
+    fn get_http_response_headers(&self) -> Vec<(String, String)> {
+        self.get_map(MapType::HttpResponseHeaders)
+    }
+
+    fn get_http_response_header(&self, name: &str) -> Option<String> {
+        self.get_map_value(MapType::HttpResponseHeaders, name)
+    }
+
+    fn set_http_response_header(&self, name: &str, value: Option<&str>) {
+        self.set_map_value(MapType::HttpResponseHeaders, name, value)
+    }
+
+    fn get_http_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        self.get_buffer(BufferType::HttpResponseBody, start, max_size)
+    }
+
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)> {
+        self.get_map(MapType::HttpCallResponseHeaders)
+    }
+
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        self.get_buffer(BufferType::HttpCallResponseBody, start, max_size)
+    }
+}
+
+// Attempts to update shared data another worker is updating at the same time
+const MAX_CAS_ATTEMPTS: usize = 8;
+
+// Writes what update returns for the current value of a shared data key, calling
+// it again with the new value when another worker wrote the key in between.
+// update returning None leaves the value as it is.
+pub fn update_shared_data<F>(host: &dyn Host, key: &str, mut update: F) -> Result<(), Status>
+where
+    F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+{
+    for _ in 0..MAX_CAS_ATTEMPTS {
+        let (value, cas) = host.get_shared_data(key);
+        let value = match update(value.as_deref()) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        match host.set_shared_data(key, Some(&value), cas) {
+            Err(Status::CasMismatch) => continue,
+            result => return result,
+        }
+    }
+    Err(Status::CasMismatch)
+}
+
+// Envoy never frees shared data keys, so state kept per user or company lives in
+// a fixed number of slot keys rather than a key per id, which ids from request
+// headers could grow without bound. Each slot holds up to MAX_SLOT_ENTRIES ids.
+const SHARED_DATA_SLOTS: u64 = 1024;
+const MAX_SLOT_ENTRIES: usize = 32;
+
+// Updates the state of id in its slot under prefix. Entries that are_stale are
+// dropped first, so update sees None for them. update returning None leaves the
+// slot as it is. A full slot evicts its least recently updated entry that isn't
+// pinned, or the least recently updated one when they all are.
+pub fn update_slot_entry<S, F, G, P>(
+    host: &dyn Host,
+    prefix: &str,
+    id: &str,
+    is_stale: G,
+    is_pinned: P,
+    mut update: F,
+) -> Result<(), Status>
+where
+    S: Serialize + DeserializeOwned,
+    F: FnMut(Option<&S>) -> Option<S>,
+    G: Fn(&S) -> bool,
+    P: Fn(&S) -> bool,
+{
+    let key = slot_key(prefix, id);
+    update_shared_data(host, &key, |value| {
+        // least recently updated first
+        let mut entries: Vec<(String, S)> = value
+            .and_then(|value| serde_json::from_slice(value).ok())
+            .unwrap_or_default();
+        entries.retain(|(_, state)| !is_stale(state));
+        let index = entries.iter().position(|(entry_id, _)| entry_id == id);
+        let state = update(index.map(|i| &entries[i].1))?;
+        if let Some(index) = index {
+            entries.remove(index);
+        }
+        entries.push((id.to_string(), state));
+        if entries.len() > MAX_SLOT_ENTRIES {
+            let evicted = entries
+                .iter()
+                .position(|(_, state)| !is_pinned(state))
+                .unwrap_or(0);
+            log::debug!("Evicting {} from shared data slot {}", entries[evicted].0, key);
+            entries.remove(evicted);
+        }
+        Some(serde_json::to_vec(&entries).unwrap())
+    })
+}
+
+// Reads the state of id in its slot under prefix
+pub fn read_slot_entry<S: DeserializeOwned>(host: &dyn Host, prefix: &str, id: &str) -> Option<S> {
+    let (value, _) = host.get_shared_data(&slot_key(prefix, id));
+    let entries: Vec<(String, S)> = serde_json::from_slice(&value?).ok()?;
+    entries
+        .into_iter()
+        .find(|(entry_id, _)| entry_id == id)
+        .map(|(_, state)| state)
+}
+
+// The shared data key of the slot of id under prefix
+pub(crate) fn slot_key(prefix: &str, id: &str) -> String {
+    format!("{}:{}", prefix, fnv1a(id) % SHARED_DATA_SLOTS)
+}
+
+// A hash that is the same in every worker's VM
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// ProxyHost makes the real host calls into Envoy, unwrapping errors the same way
+// the proxy-wasm context trait defaults do.
+pub struct ProxyHost;
+
+impl Host for ProxyHost {
+    fn get_current_time(&self) -> SystemTime {
+        hostcalls::get_current_time().unwrap()
+    }
+
+    fn set_tick_period(&self, period: Duration) {
+        hostcalls::set_tick_period(period).unwrap()
+    }
+
+    // Paths come from the plugin configuration, so an attribute the host can't
+    // read is treated as missing rather than failing the request
+    fn get_property(&self, path: Vec<&str>) -> Option<Bytes> {
+        hostcalls::get_property(path.clone()).unwrap_or_else(|e| {
+            log::debug!("Failed to read property {:?}: {:?}", path, e);
+            None
+        })
+    }
+
+    fn get_buffer(&self, buffer_type: BufferType, start: usize, max_size: usize) -> Option<Bytes> {
+        hostcalls::get_buffer(buffer_type, start, max_size).unwrap()
+    }
+
+    fn get_map(&self, map_type: MapType) -> Vec<(String, String)> {
+        hostcalls::get_map(map_type).unwrap()
+    }
+
+    fn get_map_value(&self, map_type: MapType, key: &str) -> Option<String> {
+        hostcalls::get_map_value(map_type, key).unwrap()
+    }
+
+    fn set_map_value(&self, map_type: MapType, key: &str, value: Option<&str>) {
+        hostcalls::set_map_value(map_type, key, value).unwrap()
+    }
+
+    fn register_shared_queue(&self, name: &str) -> u32 {
+        hostcalls::register_shared_queue(name).unwrap()
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        hostcalls::enqueue_shared_queue(queue_id, value)
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        hostcalls::dequeue_shared_queue(queue_id)
+    }
+
+    fn get_shared_data(&self, key: &str) -> (Option<Bytes>, Option<u32>) {
+        hostcalls::get_shared_data(key).unwrap()
+    }
+
+    fn set_shared_data(&self, key: &str, value: Option<&[u8]>, cas: Option<u32>) -> Result<(), Status> {
+        hostcalls::set_shared_data(key, value, cas)
+    }
+
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        hostcalls::dispatch_http_call(upstream, headers, body, trailers, timeout)
+    }
+
+    fn send_http_response(&self, status_code: u32, headers: Vec<(&str, &str)>, body: Option<&[u8]>) {
+        hostcalls::send_http_response(status_code, headers, body).unwrap()
+    }
+}
@@ -37,4 +37,29 @@ pub fn get_header(headers: &Headers, name: &str) -> Option<String> {
         .iter()
         .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
         .map(|(_, header_value)| header_value.to_owned())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_handler_once_for_its_token() {
+        let manager = HttpCallbackManager::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler_seen = Arc::clone(&seen);
+        manager.register_handler(
+            7,
+            Box::new(move |headers, body| {
+                let etag = get_header(&headers, "x-moesif-config-etag");
+                handler_seen.lock().unwrap().push((etag, body));
+            }),
+        );
+
+        let headers = vec![("X-Moesif-Config-Etag".to_string(), "abc".to_string())];
+        manager.handle_response(7, headers.clone(), Some(b"{}".to_vec()));
+        manager.handle_response(7, headers, None);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(*seen, vec![(Some("abc".to_string()), Some(b"{}".to_vec()))]);
+    }
+}
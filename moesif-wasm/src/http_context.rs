@@ -1,19 +1,41 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use base64::Engine as _;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use proxy_wasm::traits::{Context, HttpContext};
 use proxy_wasm::types::Action;
 
-use crate::config::Config;
+use crate::bots::BotAction;
+use crate::config::{AppConfigResponse, Config, RouteOverrides};
+use crate::dynamic_metadata::read_namespace;
 use crate::event::{Event, ResponseInfo};
+use crate::host::Host;
+use crate::identity::Stage;
+use crate::profiles::{ProfileKind, ProfileUpdate};
+use crate::quota::{count_quotas, exceeded_quota};
+use crate::rate_limit::RateLimitStatus;
+use crate::rules::{GovernanceRulesResponse, ResponseOverride};
+use crate::update_manager::UpdateManager;
+
+// filter_metadata key read from the route metadata for per-route overrides
+const ROUTE_METADATA_NAMESPACE: &str = "moesif";
+// Event.blocked_by for requests blocked by block_bot_traffic
+const BLOCKED_BY_BOT: &str = "block_bot_traffic";
 
-#[derive(Default)]
 pub(crate) struct EventHttpContext {
+    pub(crate) host: Arc<dyn Host>,
     pub(crate) config: Arc<Config>,
+    pub(crate) app_config: Arc<Mutex<UpdateManager<AppConfigResponse>>>,
+    pub(crate) governance_rules: Arc<Mutex<UpdateManager<GovernanceRulesResponse>>>,
+    pub(crate) route: RouteOverrides,
+    pub(crate) response_override: Option<ResponseOverride>,
+    pub(crate) rate_limit: Option<RateLimitStatus>,
+    pub(crate) skip: bool,
+    // governance rules on the request body wait for the end of the body
+    pub(crate) pending_enforcement: bool,
     pub(crate) event: Event,
     pub(crate) request_body: Vec<u8>,
     pub(crate) response_body: Vec<u8>,
@@ -22,15 +44,24 @@ pub(crate) struct EventHttpContext {
 impl Context for EventHttpContext {}
 
 impl HttpContext for EventHttpContext {
-    fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+    fn on_http_request_headers(&mut self, _: usize, end_of_stream: bool) -> Action {
+        self.route = RouteOverrides::from_metadata(|name| {
+            self.host.get_property(vec!["route_metadata", "filter_metadata", ROUTE_METADATA_NAMESPACE, name])
+        });
+        // skipped requests aren't captured, but are still checked against the IP
+        // blocklist, governance rules, rate limits and quotas
+        if self.route.skip == Some(true) {
+            self.skip = true;
+        }
+
         self.event.direction = "Incoming".to_string();
-        self.event.request.time = Utc::now().to_rfc3339();
+        self.event.request.time = self.now();
         self.event.request.headers =
-            EventHttpContext::header_list_to_map(self.get_http_request_headers());
+            EventHttpContext::header_list_to_map(self.host.get_http_request_headers());
         // read values from the special :path and :method headers and any other : prefixed headers before removing them
         self.event.request.uri = self.event.request.headers.get(":path").unwrap_or(&"".into()).clone();
         self.event.request.verb = self
-            .get_http_request_header(":method")
+            .host.get_http_request_header(":method")
             .unwrap_or_else(|| "GET".into());
         // remove the special : prefixed headers
         self.event
@@ -38,22 +69,83 @@ impl HttpContext for EventHttpContext {
             .headers
             .retain(|k, _| !k.starts_with(":"));
 
-        self.event.request.ip_address = EventHttpContext::get_client_ip(&self.event.request.headers);
-        self.event.request.api_version = self.get_http_request_header("x-api-version");
-        self.event.request.transfer_encoding = self.get_http_request_header("transfer-encoding");
-        
-        if let Some(user_id_header) = &self.config.env.user_id_header {
-            self.event.user_id = self.get_http_request_header(user_id_header)
+        // a route that sets skip to false is always captured
+        if self.route.skip.is_none() && self.config.skip.matches_request(&self.event.request) {
+            log::debug!("Skipping {} {}", self.event.request.verb, self.event.request.uri);
+            self.skip = true;
+        }
+
+        self.event.request.ip_address = self.config.client_ip.resolve(&self.event.request.headers, || {
+            let address = self.host.get_property(vec!["source", "address"])?;
+            String::from_utf8(address).ok()
+        });
+        self.event.request.api_version = self.host.get_http_request_header("x-api-version");
+        self.event.request.transfer_encoding = self.host.get_http_request_header("transfer-encoding");
+
+        let user_id_header = self.route.user_id_header.as_ref().or(self.config.env.user_id_header.as_ref());
+        if let Some(user_id_header) = user_id_header {
+            self.event.user_id = self.host.get_http_request_header(user_id_header)
+        }
+        let company_id_header = self.route.company_id_header.as_ref().or(self.config.env.company_id_header.as_ref());
+        if let Some(company_id_header) = company_id_header {
+            self.event.company_id = self.host.get_http_request_header(company_id_header);
+        }
+        self.config.identity.resolve(&mut self.event, self.host.as_ref(), Stage::RequestHeaders);
+
+        let host = self.host.clone();
+        self.config.env.metadata.apply_request(&mut self.event, |path| host.get_property(path));
+
+        if let Some(blocked_by) = self.ip_blocked_by() {
+            self.block(blocked_by, "Your IP address is not allowed");
+            return Action::Pause;
         }
-        if let Some(company_id_header) = &self.config.env.company_id_header {
-            self.event.company_id = self.get_http_request_header(company_id_header);
+
+        let user_agent = self.event.request.headers.get("user-agent").map(String::as_str);
+        if self.config.bots.is_bot(user_agent) {
+            if self.app_config.lock().unwrap().get_data().block_bot_traffic {
+                self.block(BLOCKED_BY_BOT.to_string(), "Bot traffic is not allowed");
+                return Action::Pause;
+            }
+            match self.config.bots.action() {
+                BotAction::Skip => {
+                    log::debug!("Skipping bot request {} {}", self.event.request.verb, self.event.request.uri);
+                    self.skip = true;
+                }
+                BotAction::Tag => self.event.set_metadata("bot", serde_json::Value::Bool(true)),
+                BotAction::Off => {}
+            }
         }
 
+        // the request is held until its body arrives, rate limits and quotas wait
+        // too so a request blocked by a rule doesn't use them up
+        if !end_of_stream && self.governance_rules.lock().unwrap().get_data().uses_request_body {
+            self.pending_enforcement = true;
+            return Action::Pause;
+        }
+        if self.enforce() {
+            return Action::Pause;
+        }
         Action::Continue
     }
 
-    fn on_http_request_body(&mut self, _num_elements: usize, end_of_stream: bool) -> Action {
-        if let Some(body_bytes) = self.get_http_request_body(0, _num_elements) {
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if self.pending_enforcement {
+            // Envoy buffers the body while the request is paused, so it is read
+            // once it is complete
+            if !end_of_stream {
+                return Action::Pause;
+            }
+            self.pending_enforcement = false;
+            let body = self.host.get_http_request_body(0, body_size).unwrap_or_default();
+            let content_type = self.event.request.headers.get("content-type");
+            self.event.request.body = EventHttpContext::body_bytes_to_value(body, content_type);
+            self.config.identity.resolve(&mut self.event, self.host.as_ref(), Stage::RequestBody);
+            return if self.enforce() { Action::Pause } else { Action::Continue };
+        }
+        if self.skip || !(self.log_body() || self.config.identity.reads_request_body()) {
+            return Action::Continue;
+        }
+        if let Some(body_bytes) = self.host.get_http_request_body(0, body_size) {
             self.request_body.extend(body_bytes);
         }
 
@@ -68,23 +160,53 @@ impl HttpContext for EventHttpContext {
     }
 
     fn on_http_response_headers(&mut self, _: usize, _: bool) -> Action {
-        let status_str = self
-            .get_http_response_header(":status")
-            .unwrap_or("0".to_string());
+        // a blocked request already has the response it was blocked with
+        if self.event.blocked_by.is_some() {
+            return Action::Continue;
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            for (name, value) in rate_limit.headers() {
+                self.host.set_http_response_header(&name, Some(&value));
+            }
+        }
+        let mut status = self.response_status();
+        // errors from the upstream are passed on without the rule headers
+        if let Some(response_override) = self.response_override.as_mut() {
+            if (200..400).contains(&status) {
+                response_override.write_response_headers(
+                    self.host.as_ref(),
+                    self.config.env.override_response_status,
+                );
+                status = self.response_status();
+            }
+        }
+        if self.skip {
+            return Action::Continue;
+        }
+        if self.route.skip.is_none() && self.config.skip.matches_status(status) {
+            log::debug!("Skipping {} {} with status {}", self.event.request.verb, self.event.request.uri, status);
+            self.skip = true;
+            return Action::Continue;
+        }
         let mut response = ResponseInfo {
-            time: Utc::now().to_rfc3339(),
-            status: status_str.parse::<usize>().unwrap_or(0),
-            headers: EventHttpContext::header_list_to_map(self.get_http_response_headers()),
-            ip_address: self.get_http_response_header("x-forwarded-for"),
+            time: self.now(),
+            status,
+            headers: EventHttpContext::header_list_to_map(self.host.get_http_response_headers()),
+            ip_address: self.host.get_http_response_header("x-forwarded-for"),
             body: serde_json::Value::Null,
         };
         response.headers.retain(|k, _| !k.starts_with(":"));
         self.event.response = Some(response);
+        self.config.env.metadata.apply_response(&mut self.event);
         Action::Continue
     }
 
     fn on_http_response_body(&mut self, num_elements: usize, end_of_stream: bool) -> Action {
-        if let Some(body_bytes) = self.get_http_response_body(0, num_elements) {
+        let reads_body = self.log_body() || self.config.identity.reads_response_body();
+        if self.skip || !reads_body || self.event.blocked_by.is_some() {
+            return Action::Continue;
+        }
+        if let Some(body_bytes) = self.host.get_http_response_body(0, num_elements) {
             self.response_body.extend(body_bytes);
         }
 
@@ -101,6 +223,36 @@ impl HttpContext for EventHttpContext {
     }
 
     fn on_log(&mut self) {
+        if self.skip {
+            return;
+        }
+        for namespace in &self.config.env.dynamic_metadata {
+            if let Some(value) = read_namespace(self.host.as_ref(), namespace) {
+                self.event.set_metadata(namespace, value);
+            }
+        }
+        self.config.identity.resolve(&mut self.event, self.host.as_ref(), Stage::Logged);
+        // bodies read only for governance rules or identity sources aren't logged
+        if !self.log_body() {
+            self.event.request.body = serde_json::Value::Null;
+            if let Some(response) = self.event.response.as_mut() {
+                response.body = serde_json::Value::Null;
+            }
+        }
+        // profiles are kept up to date from every request, sampled or not
+        self.enqueue_profiles();
+        // actions are product events, so they aren't sampled either
+        self.enqueue_actions();
+        // a route sample_rate overrides the rates from the Moesif application config
+        let sample_rate = match self.route.sample_rate {
+            Some(sample_rate) => sample_rate,
+            None => self.app_config.lock().unwrap().get_data().get_sampling_percentage(&self.event),
+        };
+        if !EventHttpContext::is_sampled(sample_rate) {
+            log::debug!("Event not sampled at {}%", sample_rate);
+            return;
+        }
+        self.event.weight = EventHttpContext::sample_weight(sample_rate);
         let json = serde_json::to_string(&self.event).unwrap();
         log::info!("Request & Response Data: {}", json);
         self.enqueue_event();
@@ -108,10 +260,203 @@ impl HttpContext for EventHttpContext {
 }
 
 impl EventHttpContext {
+    pub(crate) fn new(
+        config: Arc<Config>,
+        app_config: Arc<Mutex<UpdateManager<AppConfigResponse>>>,
+        governance_rules: Arc<Mutex<UpdateManager<GovernanceRulesResponse>>>,
+        host: Arc<dyn Host>,
+    ) -> EventHttpContext {
+        EventHttpContext {
+            host,
+            config,
+            app_config,
+            governance_rules,
+            route: RouteOverrides::default(),
+            response_override: None,
+            rate_limit: None,
+            skip: false,
+            pending_enforcement: false,
+            event: Event::default(),
+            request_body: Vec::new(),
+            response_body: Vec::new(),
+        }
+    }
+
+    fn now(&self) -> String {
+        DateTime::<Utc>::from(self.host.get_current_time()).to_rfc3339()
+    }
+
+    // Answers the request with a 403 instead of proxying it
+    fn block(&mut self, blocked_by: String, error: &str) {
+        self.block_with_status(403, Vec::new(), blocked_by, error);
+    }
+
+    fn block_with_status(&mut self, status: u32, mut headers: Vec<(String, String)>, blocked_by: String, error: &str) {
+        log::debug!(
+            "Blocking {} {} by {}",
+            self.event.request.verb,
+            self.event.request.uri,
+            blocked_by
+        );
+        headers.push(("content-type".to_string(), "application/json".to_string()));
+        let body = serde_json::json!({ "error": error });
+        let body_bytes = serde_json::to_vec(&body).unwrap();
+        self.host.send_http_response(
+            status,
+            headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+            Some(&body_bytes),
+        );
+        self.event.blocked_by = Some(blocked_by);
+        self.event.response = Some(ResponseInfo {
+            time: self.now(),
+            status: status as usize,
+            headers: headers.into_iter().collect(),
+            ip_address: None,
+            body,
+        });
+    }
+
+    // Answers the request with a 429 when its user or company bucket is empty
+    fn enforce_rate_limits(&mut self) -> bool {
+        let blocked = self.config.env.rate_limits.check(
+            self.route.rate_limit.as_deref(),
+            &self.event,
+            self.host.as_ref(),
+        );
+        match blocked {
+            Some(rate_limit) => self.block_rate_limited(rate_limit),
+            None => false,
+        }
+    }
+
+    fn block_rate_limited(&mut self, rate_limit: RateLimitStatus) -> bool {
+        let blocked_by = rate_limit.blocked_by.clone().unwrap_or_default();
+        self.block_with_status(429, rate_limit.headers(), blocked_by, "Rate limit exceeded");
+        true
+    }
+
+    // Takes a token from the request's user and company buckets and counts it
+    // against the quotas, once every limit allows it. The bucket with the fewest
+    // tokens left is reported in the X-RateLimit headers.
+    fn count_request(&mut self) -> bool {
+        let rate_limit = self.config.env.rate_limits.take(
+            self.route.rate_limit.as_deref(),
+            &self.event,
+            self.host.as_ref(),
+        );
+        if let Some(rate_limit) = rate_limit {
+            // another worker took the last token since the check
+            if rate_limit.blocked_by.is_some() {
+                return self.block_rate_limited(rate_limit);
+            }
+            self.rate_limit = Some(rate_limit);
+        }
+        count_quotas(&self.config.env.quota.quotas, &self.event, self.host.as_ref());
+        false
+    }
+
+    // Answers the request with the configured status once its user or company
+    // is over one of its quotas
+    fn enforce_quotas(&mut self) -> bool {
+        let exceeded = exceeded_quota(&self.config.env.quota.quotas, &self.event, self.host.as_ref())
+            .map(|exceeded| (exceeded.name.to_string(), exceeded.retry_after));
+        let (name, retry_after) = match exceeded {
+            Some(exceeded) => exceeded,
+            None => return false,
+        };
+        let headers = vec![("retry-after".to_string(), retry_after.to_string())];
+        self.block_with_status(self.config.env.quota.status, headers, name, "Quota exceeded");
+        true
+    }
+
+    // The name of the ip_addresses_blocked_by_name entry the client IP is in
+    fn ip_blocked_by(&self) -> Option<String> {
+        let ip = IpAddr::from_str(self.event.request.ip_address.as_deref()?).ok()?;
+        let app_config = self.app_config.lock().unwrap();
+        app_config.get_data().ip_blocklist.name_of(ip).map(String::from)
+    }
+
+    // Blocks the request if a governance rule, rate limit or quota doesn't allow
+    // it. Every limit is checked before the request is counted against any.
+    fn enforce(&mut self) -> bool {
+        self.enforce_governance_rules()
+            || self.enforce_rate_limits()
+            || self.enforce_quotas()
+            || self.count_request()
+    }
+
+    fn enforce_governance_rules(&mut self) -> bool {
+        let response_override = {
+            let governance_rules = self.governance_rules.lock().unwrap();
+            let rules = governance_rules.get_data();
+            if rules.rules.is_empty() {
+                return false;
+            }
+            let app_config = self.app_config.lock().unwrap();
+            let templates = rules.applicable_rules(&self.event, app_config.get_data());
+            if templates.is_empty() {
+                return false;
+            }
+            let response = ResponseInfo {
+                time: self.now(),
+                ..Default::default()
+            };
+            ResponseOverride::new(response, templates)
+        };
+        if !response_override.is_blocked() {
+            // non-blocking rules change the response once it arrives
+            self.response_override = Some(response_override);
+            return false;
+        }
+        log::debug!(
+            "Blocking {} {} by governance rule {:?}",
+            self.event.request.verb,
+            self.event.request.uri,
+            response_override.blocked_by()
+        );
+        response_override.send_blocked_response(self.host.as_ref());
+        self.event.blocked_by = response_override.blocked_by().map(String::from);
+        self.event.response = Some(response_override.into_blocked_response_info());
+        true
+    }
+
+    fn response_status(&self) -> usize {
+        self.host
+            .get_http_response_header(":status")
+            .and_then(|status| status.parse::<usize>().ok())
+            .unwrap_or(0)
+    }
+
+    fn log_body(&self) -> bool {
+        self.route.log_body.unwrap_or(self.config.env.log_body)
+    }
+
+    // sample_rate is a percentage, events are always kept at 100 and dropped at 0
+    fn is_sampled(sample_rate: i32) -> bool {
+        if sample_rate >= 100 {
+            return true;
+        }
+        let mut bytes = [0u8; 4];
+        if let Err(e) = getrandom::getrandom(&mut bytes) {
+            log::error!("Failed to get random bytes for sampling: {:?}", e);
+            return true;
+        }
+        (u32::from_le_bytes(bytes) % 100) < sample_rate.max(0) as u32
+    }
+
+    // Moesif scales sampled events by their weight so the reported volume stays accurate
+    fn sample_weight(sample_rate: i32) -> Option<i32> {
+        if sample_rate > 0 && sample_rate < 100 {
+            Some(100 / sample_rate)
+        } else {
+            None
+        }
+    }
+
     fn enqueue_event(self: &EventHttpContext) {
         let event_bytes = serde_json::to_vec(&self.event).unwrap();
 
-        match self.enqueue_shared_queue(self.config.event_queue_id, Some(&event_bytes)) {
+        match self.host.enqueue_shared_queue(self.config.event_queue_id, Some(&event_bytes)) {
             Ok(_) => {
                 log::info!("Enqueued event to shared queue");
             }
@@ -121,6 +466,53 @@ impl EventHttpContext {
         }
     }
 
+    // Sends the configured profile fields of the event's user and company to the
+    // root context, which batches them
+    fn enqueue_profiles(&self) {
+        let profiles = &self.config.env.profiles;
+        if profiles.is_empty() {
+            return;
+        }
+        let host = self.host.clone();
+        let get_property = |path: Vec<&str>| host.get_property(path);
+        let mut updates = Vec::new();
+        if let Some(user_id) = &self.event.user_id {
+            updates.push(ProfileUpdate {
+                kind: ProfileKind::User,
+                id: user_id.clone(),
+                company_id: self.event.company_id.clone(),
+                metadata: profiles.user.values(&self.event, get_property),
+            });
+        }
+        if let Some(company_id) = &self.event.company_id {
+            updates.push(ProfileUpdate {
+                kind: ProfileKind::Company,
+                id: company_id.clone(),
+                company_id: None,
+                metadata: profiles.company.values(&self.event, get_property),
+            });
+        }
+        for update in updates.into_iter().filter(|update| !update.metadata.is_empty()) {
+            let update_bytes = serde_json::to_vec(&update).unwrap();
+            if let Err(e) = self.host.enqueue_shared_queue(self.config.profile_queue_id, Some(&update_bytes)) {
+                log::error!("Failed to enqueue profile update: {:?}", e);
+            }
+        }
+    }
+
+    fn enqueue_actions(&self) {
+        if self.config.actions.is_empty() {
+            return;
+        }
+        let host = self.host.clone();
+        for action in self.config.actions.actions(&self.event, |path| host.get_property(path)) {
+            let action_bytes = serde_json::to_vec(&action).unwrap();
+            if let Err(e) = self.host.enqueue_shared_queue(self.config.action_queue_id, Some(&action_bytes)) {
+                log::error!("Failed to enqueue action: {:?}", e);
+            }
+        }
+    }
+
     fn header_list_to_map(headers: Vec<(String, String)>) -> HashMap<String, String> {
         headers
             .into_iter()
@@ -128,42 +520,13 @@ impl EventHttpContext {
             .collect::<HashMap<_, _>>()
     }
 
-    fn get_client_ip(headers: &HashMap<String, String>) -> Option<String> {
-        let possible_headers = vec![
-            "x-client-ip",
-            "x-forwarded-for",
-            "cf-connecting-ip",
-            "fastly-client-ip",
-            "true-client-ip",
-            "x-real-ip",
-            "x-cluster-client-ip",
-            "x-forwarded",
-            "forwarded-for",
-            "forwarded",
-            "x-appengine-user-ip",
-            "cf-pseudo-ipv4",
-        ];
-
-        for header in possible_headers {
-            if let Some(value) = headers.get(header) {
-                let ips: Vec<&str> = value.split(",").collect();
-                for ip in ips {
-                    if IpAddr::from_str(ip.trim()).is_ok() {
-                        return Some(ip.trim().to_string());
-                    }
-                }
-            }
-        }
-        None
-    }
-
     fn body_bytes_to_value(body: Vec<u8>, content_type: Option<&String>) -> serde_json::Value {
         if body.is_empty() {
             return serde_json::Value::Null;
         }
 
         if let Some(content_type) = content_type {
-            if content_type.as_str() == "application/json" {
+            if is_json(content_type) {
                 return match serde_json::from_slice::<serde_json::Value>(&body) {
                     Ok(json) => json,
                     Err(_) => {
@@ -178,3 +541,38 @@ impl EventHttpContext {
         serde_json::Value::String(body_str)
     }
 }
+
+// Whether a content-type is JSON, ignoring parameters such as charset
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case("application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn body_is_json_or_text() {
+        let json_type = "application/json".to_string();
+        assert_eq!(
+            EventHttpContext::body_bytes_to_value(br#"{"a":1}"#.to_vec(), Some(&json_type)),
+            json!({"a": 1})
+        );
+        assert_eq!(
+            EventHttpContext::body_bytes_to_value(b"{bad".to_vec(), Some(&json_type)),
+            json!("e2JhZA==")
+        );
+        assert_eq!(
+            EventHttpContext::body_bytes_to_value(b"hello".to_vec(), None),
+            json!("hello")
+        );
+        assert_eq!(EventHttpContext::body_bytes_to_value(Vec::new(), None), json!(null));
+        let charset = "Application/JSON; charset=utf-8".to_string();
+        assert_eq!(
+            EventHttpContext::body_bytes_to_value(br#"{"a":1}"#.to_vec(), Some(&charset)),
+            json!({"a": 1})
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Named sets of addresses and CIDR ranges, such as ip_addresses_blocked_by_name which
+// maps a name to a comma or whitespace separated list like "203.0.113.7, 10.0.0.0/8,
+// 2001:db8::/32". Each address family is a binary trie over the address bits, so a
+// lookup walks at most 32 or 128 nodes however many ranges there are.
+#[derive(Default, Debug, Clone)]
+pub struct IpRanges {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+    names: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone)]
+struct PrefixTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Node {
+    // index of the child node for a 0 or 1 bit, 0 for none as the root is never a child
+    children: [usize; 2],
+    // index into IpRanges.names when a prefix ends here
+    name: Option<usize>,
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: u128, width: u32, prefix_len: u32, name: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for i in 0..prefix_len {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(Node::default());
+                self.nodes[node].children[bit] = self.nodes.len() - 1;
+            }
+            node = self.nodes[node].children[bit];
+        }
+        // the first name given for a range wins
+        self.nodes[node].name.get_or_insert(name);
+    }
+
+    // The name of the longest prefix containing the address
+    fn lookup(&self, bits: u128, width: u32) -> Option<usize> {
+        let mut node = self.nodes.first()?;
+        let mut found = node.name;
+        for i in 0..width {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            match node.children[bit] {
+                0 => break,
+                child => node = &self.nodes[child],
+            }
+            found = node.name.or(found);
+        }
+        found
+    }
+}
+
+impl IpRanges {
+    // Entries that are not an address or CIDR range are logged and ignored
+    pub fn new(ranges_by_name: &HashMap<String, String>) -> IpRanges {
+        let mut ranges = IpRanges::default();
+        // sorted so the name reported for a range listed twice doesn't depend on map order
+        let mut names: Vec<&String> = ranges_by_name.keys().collect();
+        names.sort();
+        for name in names {
+            let name_index = ranges.names.len();
+            ranges.names.push(name.clone());
+            let entries = ranges_by_name[name]
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|entry| !entry.is_empty());
+            for entry in entries {
+                match parse_cidr(entry) {
+                    Some((IpAddr::V4(ip), prefix_len)) => {
+                        ranges
+                            .v4
+                            .insert(u32::from(ip) as u128, 32, prefix_len, name_index)
+                    }
+                    Some((IpAddr::V6(ip), prefix_len)) => {
+                        ranges
+                            .v6
+                            .insert(u128::from(ip), 128, prefix_len, name_index)
+                    }
+                    None => log::warn!(
+                        "Ignoring invalid IP address or range {:?} in {}",
+                        entry,
+                        name
+                    ),
+                }
+            }
+        }
+        ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.name_of(ip).is_some()
+    }
+
+    // The name of the entry containing the address, IPv4-mapped IPv6 addresses are checked as IPv4
+    pub fn name_of(&self, ip: IpAddr) -> Option<&str> {
+        let index = match ip {
+            IpAddr::V4(ip) => self.v4.lookup(u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.v4.lookup(u32::from(ip) as u128, 32),
+                None => self.v6.lookup(u128::from(ip), 128),
+            },
+        };
+        index.map(|i| self.names[i].as_str())
+    }
+}
+
+pub(crate) fn parse_cidr(entry: &str) -> Option<(IpAddr, u32)> {
+    let (ip, prefix_len) = match entry.split_once('/') {
+        Some((ip, prefix_len)) => (
+            ip.parse::<IpAddr>().ok()?,
+            Some(prefix_len.parse::<u32>().ok()?),
+        ),
+        None => (entry.parse::<IpAddr>().ok()?, None),
+    };
+    let width = if ip.is_ipv4() { 32 } else { 128 };
+    match prefix_len {
+        Some(prefix_len) if prefix_len > width => None,
+        Some(prefix_len) => Some((ip, prefix_len)),
+        None => Some((ip, width)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_addresses_and_ranges_of_both_families() {
+        let blocked_by_name: HashMap<String, String> = vec![
+            ("office".to_string(), "10.0.0.0/8, 203.0.113.7".to_string()),
+            (
+                "lab".to_string(),
+                "10.1.0.0/16 2001:db8::/32 not-an-ip 10.0.0.0/33".to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let ranges = IpRanges::new(&blocked_by_name);
+        let blocked_by = |ip: &str| ranges.name_of(ip.parse().unwrap());
+
+        assert_eq!(blocked_by("10.200.0.1"), Some("office"));
+        assert_eq!(blocked_by("10.1.2.3"), Some("lab"));
+        assert_eq!(blocked_by("203.0.113.7"), Some("office"));
+        assert_eq!(blocked_by("::ffff:203.0.113.7"), Some("office"));
+        assert_eq!(blocked_by("2001:db8:1::1"), Some("lab"));
+        assert_eq!(blocked_by("203.0.113.8"), None);
+        assert_eq!(blocked_by("2001:db9::1"), None);
+        assert_eq!(
+            IpRanges::default().name_of("10.0.0.1".parse().unwrap()),
+            None
+        );
+    }
+}
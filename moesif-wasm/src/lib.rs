@@ -1,17 +1,35 @@
+mod actions;
+mod bots;
+mod client_ip;
+mod conditions;
 mod config;
+mod dynamic_metadata;
 mod event;
+pub mod host;
 mod http_context;
-mod root_context;
+mod identity;
+mod ip_ranges;
+mod metadata;
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "mock")))]
+pub mod mock_host;
+mod profiles;
+mod quota;
+mod rate_limit;
+pub mod root_context;
 mod http_callback;
+mod skip;
 mod rules;
 mod update_manager;
 
+use std::sync::Arc;
+
+use host::ProxyHost;
 use proxy_wasm::{traits::RootContext, types::LogLevel};
 use root_context::EventRootContext;
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Debug);
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> { 
-        Box::new(EventRootContext::default()) 
+        Box::new(EventRootContext::new(Arc::new(ProxyHost)))
     });
 }}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::validate_header_name;
+use crate::dynamic_metadata::decode_struct;
+use crate::event::{json_value, Event};
+
+// Where the value of a metadata field comes from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    Value(Value),
+    RequestHeader(String),
+    ResponseHeader(String),
+    // an Envoy attribute such as xds.cluster_name
+    Property(PropertyPath),
+    // a dotted path into the claims of the request's bearer JWT
+    JwtClaim(String),
+}
+
+// "xds.cluster_name" or ["xds", "cluster_name"], the list form allows segments
+// with dots such as filter names
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PropertyPath {
+    Dotted(String),
+    Segments(Vec<String>),
+}
+
+impl PropertyPath {
+    pub fn segments(&self) -> Vec<&str> {
+        match self {
+            PropertyPath::Dotted(path) => path.split('.').collect(),
+            PropertyPath::Segments(segments) => segments.iter().map(String::as_str).collect(),
+        }
+    }
+
+    // The value of the attribute read from this path. Envoy returns node.metadata
+    // and the namespaces of filter_metadata as serialized google.protobuf.Structs,
+    // which become objects. Other attributes are only used when they are strings,
+    // since numbers, booleans and messages come back as binary.
+    pub fn value(&self, bytes: Vec<u8>) -> Option<Value> {
+        match self.segments().as_slice() {
+            ["node", "metadata"] | [.., "filter_metadata", _] => decode_struct(&bytes),
+            _ => property_string(bytes).map(Value::String),
+        }
+    }
+}
+
+// The string value of an attribute, None for binary values
+pub fn property_string(bytes: Vec<u8>) -> Option<String> {
+    String::from_utf8(bytes)
+        .ok()
+        .filter(|value| !value.chars().any(char::is_control))
+}
+
+// The `metadata` section of the plugin configuration, field name -> source.
+// Fields whose source has no value on a request are left out.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MetadataConfig {
+    pub fields: HashMap<String, MetadataSource>,
+}
+
+impl MetadataConfig {
+    // section is the configuration field holding these fields, for error messages
+    pub fn validate(&self, section: &str, errors: &mut Vec<String>) {
+        for (name, source) in &self.fields {
+            if name.is_empty() {
+                errors.push(format!("{} field names must not be empty", section));
+            }
+            let field = format!("{}.{}", section, name);
+            match source {
+                MetadataSource::RequestHeader(header) | MetadataSource::ResponseHeader(header) => {
+                    validate_header_name(&field, Some(header), errors)
+                }
+                MetadataSource::Property(path) => {
+                    if path.segments().iter().any(|segment| segment.is_empty()) {
+                        errors.push(format!("{} property path has an empty segment", field));
+                    }
+                }
+                MetadataSource::JwtClaim(path) => {
+                    if path.is_empty() {
+                        errors.push(format!("{} jwt_claim must not be empty", field));
+                    }
+                }
+                MetadataSource::Value(_) => {}
+            }
+        }
+    }
+
+    // Sets the fields known once the request headers arrive. get_property reads an
+    // Envoy attribute, see PropertyPath::value for how its bytes are used.
+    pub fn apply_request<F>(&self, event: &mut Event, get_property: F)
+    where
+        F: Fn(Vec<&str>) -> Option<Vec<u8>>,
+    {
+        for (name, source) in &self.fields {
+            if let MetadataSource::ResponseHeader(_) = source {
+                continue;
+            }
+            if let Some(value) = source.value(event, &get_property) {
+                event.set_metadata(name, value);
+            }
+        }
+    }
+
+    // Sets the fields from response headers once the response headers arrive
+    pub fn apply_response(&self, event: &mut Event) {
+        for (name, source) in &self.fields {
+            if let MetadataSource::ResponseHeader(_) = source {
+                if let Some(value) = source.value(event, &|_| None) {
+                    event.set_metadata(name, value);
+                }
+            }
+        }
+    }
+
+    // Every field with a value, for a complete request and response
+    pub fn values<F>(&self, event: &Event, get_property: F) -> Map<String, Value>
+    where
+        F: Fn(Vec<&str>) -> Option<Vec<u8>>,
+    {
+        self.fields
+            .iter()
+            .filter_map(|(name, source)| Some((name.clone(), source.value(event, &get_property)?)))
+            .collect()
+    }
+}
+
+impl MetadataSource {
+    fn value<F>(&self, event: &Event, get_property: &F) -> Option<Value>
+    where
+        F: Fn(Vec<&str>) -> Option<Vec<u8>>,
+    {
+        match self {
+            MetadataSource::Value(value) => Some(value.clone()),
+            MetadataSource::RequestHeader(header) => {
+                let value = event.request.headers.get(&header.to_lowercase())?;
+                Some(Value::String(value.clone()))
+            }
+            MetadataSource::ResponseHeader(header) => {
+                let response = event.response.as_ref()?;
+                let value = response.headers.get(&header.to_lowercase())?;
+                Some(Value::String(value.clone()))
+            }
+            MetadataSource::Property(path) => path.value(get_property(path.segments())?),
+            MetadataSource::JwtClaim(path) => {
+                let payload = jwt_payload(event.request.headers.get("authorization")?)?;
+                json_value(&payload, path).filter(|value| !value.is_null()).cloned()
+            }
+        }
+    }
+}
+
+// The payload of a bearer JWT. The signature isn't verified, so claims should only
+// be used to describe a caller another filter has already authenticated.
+fn jwt_payload(authorization: &str) -> Option<Value> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let payload = token.trim().split('.').nth(1)?;
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    serde_json::from_slice(&decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResponseInfo;
+    use serde_json::json;
+
+    #[test]
+    fn fields_are_set_from_their_sources() {
+        let config: MetadataConfig = serde_json::from_value(json!({
+            "environment": {"value": "production"},
+            "tenant": {"request_header": "X-Tenant"},
+            "cache": {"response_header": "x-cache"},
+            "cluster": {"property": "xds.cluster_name"},
+            "principal": {"property": ["source", "principal"]},
+            "missing": {"request_header": "x-missing"}
+        }))
+        .unwrap();
+        let mut errors = Vec::new();
+        config.validate("metadata", &mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let mut event = Event::default();
+        event
+            .request
+            .headers
+            .insert("x-tenant".to_string(), "acme".to_string());
+        config.apply_request(&mut event, |path| match path.as_slice() {
+            ["xds", "cluster_name"] => Some(b"orders".to_vec()),
+            _ => None,
+        });
+        event.response = Some(ResponseInfo {
+            headers: vec![("x-cache".to_string(), "HIT".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        });
+        config.apply_response(&mut event);
+        assert_eq!(
+            event.metadata,
+            json!({"environment": "production", "tenant": "acme", "cache": "HIT", "cluster": "orders"})
+        );
+
+        let invalid: MetadataConfig = serde_json::from_value(json!({
+            "a": {"request_header": "bad header"},
+            "b": {"property": "xds..cluster_name"}
+        }))
+        .unwrap();
+        let mut errors = Vec::new();
+        invalid.validate("metadata", &mut errors);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn struct_properties_are_decoded() {
+        let config: MetadataConfig = serde_json::from_value(json!({
+            "node": {"property": "node.metadata"},
+            "code": {"property": "response.code"},
+            "cluster": {"property": "xds.cluster_name"}
+        }))
+        .unwrap();
+        // a google.protobuf.Struct of {"region": "us-east-1"}
+        let mut node_metadata = vec![0x0a, 21, 0x0a, 6];
+        node_metadata.extend_from_slice(b"region");
+        node_metadata.extend_from_slice(&[0x12, 11, 0x1a, 9]);
+        node_metadata.extend_from_slice(b"us-east-1");
+        let values = config.values(&Event::default(), |path| match path.as_slice() {
+            ["node", "metadata"] => Some(node_metadata.clone()),
+            // a 64-bit integer
+            ["response", "code"] => Some(200u64.to_le_bytes().to_vec()),
+            ["xds", "cluster_name"] => Some(b"orders".to_vec()),
+            _ => None,
+        });
+        assert_eq!(
+            Value::Object(values),
+            json!({"node": {"region": "us-east-1"}, "cluster": "orders"})
+        );
+    }
+
+    #[test]
+    fn jwt_claims_are_read_from_bearer_tokens() {
+        let config: MetadataConfig = serde_json::from_value(json!({
+            "email": {"jwt_claim": "email"},
+            "plan": {"jwt_claim": "app_metadata.plan"},
+            "missing": {"jwt_claim": "name"}
+        }))
+        .unwrap();
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"email": "jane@example.com", "app_metadata": {"plan": "pro"}}"#);
+        let mut event = Event::default();
+        event.request.headers.insert(
+            "authorization".to_string(),
+            format!("bearer e30.{}.signature", claims),
+        );
+        assert_eq!(
+            Value::Object(config.values(&event, |_| None)),
+            json!({"email": "jane@example.com", "plan": "pro"})
+        );
+        event
+            .request
+            .headers
+            .insert("authorization".to_string(), "Basic e30.e30.e30".to_string());
+        assert!(config.values(&event, |_| None).is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use proxy_wasm::types::{BufferType, Bytes, MapType, Status};
+
+use crate::host::Host;
+
+// HttpCall is a request the plugin dispatched through MockHost::dispatch_http_call
+#[derive(Debug, Clone)]
+pub struct HttpCall {
+    pub token_id: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Bytes>,
+    pub timeout: Duration,
+}
+
+impl HttpCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn body_json(&self) -> serde_json::Value {
+        serde_json::from_slice(self.body.as_deref().unwrap_or_default()).unwrap()
+    }
+}
+
+// LocalResponse is a response the plugin sent with MockHost::send_http_response
+#[derive(Debug, Clone)]
+pub struct LocalResponse {
+    pub status_code: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Bytes>,
+}
+
+impl LocalResponse {
+    pub fn body_json(&self) -> serde_json::Value {
+        serde_json::from_slice(self.body.as_deref().unwrap_or_default()).unwrap()
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    current_time: Option<SystemTime>,
+    tick_period: Option<Duration>,
+    properties: HashMap<Vec<String>, Bytes>,
+    buffers: HashMap<BufferType, Bytes>,
+    maps: HashMap<MapType, Vec<(String, String)>>,
+    queue_names: HashMap<String, u32>,
+    queues: HashMap<u32, VecDeque<Bytes>>,
+    shared_data: HashMap<String, (Bytes, u32)>,
+    http_calls: Vec<HttpCall>,
+    next_token_id: u32,
+    local_responses: Vec<LocalResponse>,
+}
+
+// MockHost is an in-memory stand-in for Envoy. Tests set the request and response
+// data a context will read, drive the context callbacks directly and then inspect
+// what the plugin enqueued and dispatched.
+#[derive(Default)]
+pub struct MockHost {
+    state: Mutex<MockState>,
+}
+
+impl MockHost {
+    pub fn new() -> MockHost {
+        MockHost::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn set_current_time(&self, time: SystemTime) {
+        self.state().current_time = Some(time);
+    }
+
+    pub fn tick_period(&self) -> Option<Duration> {
+        self.state().tick_period
+    }
+
+    pub fn set_vm_configuration(&self, config: &str) {
+        self.set_buffer(BufferType::VmConfiguration, config.as_bytes());
+    }
+
+    pub fn set_plugin_configuration(&self, config: &str) {
+        self.set_buffer(BufferType::PluginConfiguration, config.as_bytes());
+    }
+
+    pub fn set_property(&self, path: &[&str], value: &[u8]) {
+        let path = path.iter().map(|p| p.to_string()).collect();
+        self.state().properties.insert(path, value.to_vec());
+    }
+
+    pub fn clear_properties(&self) {
+        self.state().properties.clear();
+    }
+
+    pub fn set_buffer(&self, buffer_type: BufferType, value: &[u8]) {
+        self.state().buffers.insert(buffer_type, value.to_vec());
+    }
+
+    pub fn set_map(&self, map_type: MapType, map: Vec<(&str, &str)>) {
+        let map = map
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        self.state().maps.insert(map_type, map);
+    }
+
+    pub fn set_http_request_headers(&self, headers: Vec<(&str, &str)>) {
+        self.set_map(MapType::HttpRequestHeaders, headers);
+    }
+
+    // The number of shared data keys written
+    pub fn shared_data_len(&self) -> usize {
+        self.state().shared_data.len()
+    }
+
+    pub fn set_http_request_body(&self, body: &[u8]) {
+        self.set_buffer(BufferType::HttpRequestBody, body);
+    }
+
+    pub fn set_http_response_headers(&self, headers: Vec<(&str, &str)>) {
+        self.set_map(MapType::HttpResponseHeaders, headers);
+    }
+
+    pub fn set_http_response_body(&self, body: &[u8]) {
+        self.set_buffer(BufferType::HttpResponseBody, body);
+    }
+
+    // The response the next on_http_call_response will read
+    pub fn set_http_call_response(&self, headers: Vec<(&str, &str)>, body: &[u8]) {
+        self.set_map(MapType::HttpCallResponseHeaders, headers);
+        self.set_buffer(BufferType::HttpCallResponseBody, body);
+    }
+
+    // The id a queue was registered with, None if it wasn't
+    pub fn queue_id(&self, name: &str) -> Option<u32> {
+        self.state().queue_names.get(name).copied()
+    }
+
+    pub fn queue_len(&self, queue_id: u32) -> usize {
+        self.state().queues.get(&queue_id).map_or(0, |q| q.len())
+    }
+
+    // Removes and returns every call dispatched since the last take
+    pub fn take_http_calls(&self) -> Vec<HttpCall> {
+        std::mem::take(&mut self.state().http_calls)
+    }
+
+    // Removes and returns every local response sent since the last take
+    pub fn take_local_responses(&self) -> Vec<LocalResponse> {
+        std::mem::take(&mut self.state().local_responses)
+    }
+}
+
+impl Host for MockHost {
+    fn get_current_time(&self) -> SystemTime {
+        self.state().current_time.unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    fn set_tick_period(&self, period: Duration) {
+        self.state().tick_period = Some(period);
+    }
+
+    fn get_property(&self, path: Vec<&str>) -> Option<Bytes> {
+        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
+        self.state().properties.get(&path).cloned()
+    }
+
+    fn get_buffer(&self, buffer_type: BufferType, start: usize, max_size: usize) -> Option<Bytes> {
+        let state = self.state();
+        let buffer = state.buffers.get(&buffer_type)?;
+        if start >= buffer.len() {
+            return None;
+        }
+        let end = buffer.len().min(start.saturating_add(max_size));
+        Some(buffer[start..end].to_vec())
+    }
+
+    fn get_map(&self, map_type: MapType) -> Vec<(String, String)> {
+        self.state()
+            .maps
+            .get(&map_type)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Header lookups are case insensitive like Envoy's
+    fn get_map_value(&self, map_type: MapType, key: &str) -> Option<String> {
+        self.state()
+            .maps
+            .get(&map_type)?
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone())
+    }
+
+    // Replaces every value of the key, None removes it
+    fn set_map_value(&self, map_type: MapType, key: &str, value: Option<&str>) {
+        let mut state = self.state();
+        let map = state.maps.entry(map_type).or_default();
+        map.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        if let Some(value) = value {
+            map.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    fn register_shared_queue(&self, name: &str) -> u32 {
+        let mut state = self.state();
+        let next_id = state.queue_names.len() as u32 + 1;
+        let queue_id = *state.queue_names.entry(name.to_string()).or_insert(next_id);
+        state.queues.entry(queue_id).or_default();
+        queue_id
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        match self.state().queues.get_mut(&queue_id) {
+            Some(queue) => {
+                queue.push_back(value.unwrap_or_default().to_vec());
+                Ok(())
+            }
+            None => Err(Status::NotFound),
+        }
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        match self.state().queues.get_mut(&queue_id) {
+            Some(queue) => Ok(queue.pop_front()),
+            None => Err(Status::NotFound),
+        }
+    }
+
+    fn get_shared_data(&self, key: &str) -> (Option<Bytes>, Option<u32>) {
+        match self.state().shared_data.get(key) {
+            Some((value, cas)) => (Some(value.clone()), Some(*cas)),
+            None => (None, None),
+        }
+    }
+
+    // Like Envoy, a cas of None or 0 always writes and every write changes the cas
+    fn set_shared_data(&self, key: &str, value: Option<&[u8]>, cas: Option<u32>) -> Result<(), Status> {
+        let mut state = self.state();
+        let current_cas = state.shared_data.get(key).map_or(0, |(_, cas)| *cas);
+        if let Some(cas) = cas.filter(|cas| *cas != 0) {
+            if cas != current_cas {
+                return Err(Status::CasMismatch);
+            }
+        }
+        let value = value.unwrap_or_default().to_vec();
+        state.shared_data.insert(key.to_string(), (value, current_cas + 1));
+        Ok(())
+    }
+
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        _trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        let mut state = self.state();
+        state.next_token_id += 1;
+        let token_id = state.next_token_id;
+        state.http_calls.push(HttpCall {
+            token_id,
+            upstream: upstream.to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.map(|b| b.to_vec()),
+            timeout,
+        });
+        Ok(token_id)
+    }
+
+    fn send_http_response(&self, status_code: u32, headers: Vec<(&str, &str)>, body: Option<&[u8]>) {
+        self.state().local_responses.push(LocalResponse {
+            status_code,
+            headers: headers
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.map(|b| b.to_vec()),
+        });
+    }
+}
+
+// The proxy-wasm trait default methods end up in the contexts' vtables, so any
+// host build that uses a context as a trait object needs the host ABI symbols
+// to link. They are never called because every host call goes through Host,
+// and a stray call fails with InternalFailure which proxy-wasm turns into a panic.
+macro_rules! unsupported_host_calls {
+    ($(fn $name:ident($($ty:ty),*);)*) => {
+        $(
+            #[no_mangle]
+            pub extern "C" fn $name($(_: $ty),*) -> Status {
+                Status::InternalFailure
+            }
+        )*
+    };
+}
+
+mod abi {
+    use proxy_wasm::types::{BufferType, LogLevel, MapType, MetricType, Status, StreamType};
+
+    unsupported_host_calls! {
+        fn proxy_log(LogLevel, *const u8, usize);
+        fn proxy_get_log_level(*mut LogLevel);
+        fn proxy_get_current_time_nanoseconds(*mut u64);
+        fn proxy_set_tick_period_milliseconds(u32);
+        fn proxy_get_buffer_bytes(BufferType, usize, usize, *mut *mut u8, *mut usize);
+        fn proxy_set_buffer_bytes(BufferType, usize, usize, *const u8, usize);
+        fn proxy_get_header_map_pairs(MapType, *mut *mut u8, *mut usize);
+        fn proxy_set_header_map_pairs(MapType, *const u8, usize);
+        fn proxy_get_header_map_value(MapType, *const u8, usize, *mut *mut u8, *mut usize);
+        fn proxy_replace_header_map_value(MapType, *const u8, usize, *const u8, usize);
+        fn proxy_remove_header_map_value(MapType, *const u8, usize);
+        fn proxy_add_header_map_value(MapType, *const u8, usize, *const u8, usize);
+        fn proxy_get_property(*const u8, usize, *mut *mut u8, *mut usize);
+        fn proxy_set_property(*const u8, usize, *const u8, usize);
+        fn proxy_get_shared_data(*const u8, usize, *mut *mut u8, *mut usize, *mut u32);
+        fn proxy_set_shared_data(*const u8, usize, *const u8, usize, u32);
+        fn proxy_register_shared_queue(*const u8, usize, *mut u32);
+        fn proxy_resolve_shared_queue(*const u8, usize, *const u8, usize, *mut u32);
+        fn proxy_dequeue_shared_queue(u32, *mut *mut u8, *mut usize);
+        fn proxy_enqueue_shared_queue(u32, *const u8, usize);
+        fn proxy_continue_stream(StreamType);
+        fn proxy_close_stream(StreamType);
+        fn proxy_send_local_response(u32, *const u8, usize, *const u8, usize, *const u8, usize, i32);
+        fn proxy_http_call(*const u8, usize, *const u8, usize, *const u8, usize, *const u8, usize, u32, *mut u32);
+        fn proxy_grpc_call(*const u8, usize, *const u8, usize, *const u8, usize, *const u8, usize, *const u8, usize, u32, *mut u32);
+        fn proxy_grpc_stream(*const u8, usize, *const u8, usize, *const u8, usize, *const u8, usize, *mut u32);
+        fn proxy_grpc_send(u32, *const u8, usize, bool);
+        fn proxy_grpc_cancel(u32);
+        fn proxy_grpc_close(u32);
+        fn proxy_get_status(*mut u32, *mut *mut u8, *mut usize);
+        fn proxy_set_effective_context(u32);
+        fn proxy_call_foreign_function(*const u8, usize, *const u8, usize, *mut *mut u8, *mut usize);
+        fn proxy_done();
+        fn proxy_define_metric(MetricType, *const u8, usize, *mut u32);
+        fn proxy_get_metric(u32, *mut u64);
+        fn proxy_record_metric(u32, u64);
+        fn proxy_increment_metric(u32, i64);
+    }
+}
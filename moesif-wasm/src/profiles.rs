@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::metadata::MetadataConfig;
+
+fn default_ttl_seconds() -> i64 {
+    3600
+}
+
+// The `profiles` section of the plugin configuration, the metadata fields of the
+// user and company profiles kept up to date from traffic
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileConfig {
+    #[serde(default)]
+    pub user: MetadataConfig,
+    #[serde(default)]
+    pub company: MetadataConfig,
+    // an unchanged profile is sent again after this long
+    #[serde(default = "default_ttl_seconds")]
+    pub ttl_seconds: i64,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> ProfileConfig {
+        ProfileConfig {
+            user: MetadataConfig::default(),
+            company: MetadataConfig::default(),
+            ttl_seconds: default_ttl_seconds(),
+        }
+    }
+}
+
+impl ProfileConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        self.user.validate("profiles.user", errors);
+        self.company.validate("profiles.company", errors);
+        if self.ttl_seconds <= 0 {
+            errors.push(format!(
+                "profiles.ttl_seconds must be greater than 0, got {}",
+                self.ttl_seconds
+            ));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user.fields.is_empty() && self.company.fields.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileKind {
+    User,
+    Company,
+}
+
+// A profile seen by an http context, sent to the root context on the profile queue
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProfileUpdate {
+    pub kind: ProfileKind,
+    pub id: String,
+    // the company of a user
+    pub company_id: Option<String>,
+    pub metadata: Map<String, Value>,
+}
+
+impl ProfileUpdate {
+    // The Moesif user or company model
+    fn to_json(&self) -> Value {
+        match self.kind {
+            ProfileKind::User => {
+                let mut user = json!({"user_id": self.id, "metadata": self.metadata});
+                if let Some(company_id) = &self.company_id {
+                    user["company_id"] = Value::String(company_id.clone());
+                }
+                user
+            }
+            ProfileKind::Company => json!({"company_id": self.id, "metadata": self.metadata}),
+        }
+    }
+}
+
+// Collects profile updates between batches. Each profile is sent once per batch
+// with its latest values, and not again until it changes or ttl_seconds pass.
+#[derive(Default)]
+pub struct ProfileBatcher {
+    pending: HashMap<(ProfileKind, String), Value>,
+    // the profiles sent and when
+    sent: HashMap<(ProfileKind, String), (Value, i64)>,
+}
+
+impl ProfileBatcher {
+    pub fn add(&mut self, update: ProfileUpdate, ttl_seconds: i64, now: i64) {
+        let key = (update.kind, update.id.clone());
+        let profile = update.to_json();
+        if let Some((sent, sent_at)) = self.sent.get(&key) {
+            if *sent == profile && now - sent_at < ttl_seconds {
+                self.pending.remove(&key);
+                return;
+            }
+        }
+        self.pending.insert(key, profile);
+    }
+
+    pub fn len(&self, kind: ProfileKind) -> usize {
+        self.pending.keys().filter(|(k, _)| *k == kind).count()
+    }
+
+    // Removes the pending profiles of a kind to send them, remembering them as sent
+    pub fn take(&mut self, kind: ProfileKind, ttl_seconds: i64, now: i64) -> Vec<Value> {
+        self.sent
+            .retain(|_, (_, sent_at)| now - *sent_at < ttl_seconds);
+        let keys: Vec<(ProfileKind, String)> = self
+            .pending
+            .keys()
+            .filter(|(k, _)| *k == kind)
+            .cloned()
+            .collect();
+        let mut profiles = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(profile) = self.pending.remove(&key) {
+                self.sent.insert(key, (profile.clone(), now));
+                profiles.push(profile);
+            }
+        }
+        profiles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(kind: ProfileKind, id: &str, plan: &str) -> ProfileUpdate {
+        let mut metadata = Map::new();
+        metadata.insert("plan".to_string(), Value::String(plan.to_string()));
+        ProfileUpdate {
+            kind,
+            id: id.to_string(),
+            company_id: None,
+            metadata,
+        }
+    }
+
+    #[test]
+    fn profiles_are_deduplicated_until_they_change_or_expire() {
+        let mut batcher = ProfileBatcher::default();
+        batcher.add(update(ProfileKind::User, "u1", "free"), 60, 0);
+        batcher.add(update(ProfileKind::User, "u1", "pro"), 60, 0);
+        batcher.add(update(ProfileKind::Company, "acme", "pro"), 60, 0);
+        assert_eq!(batcher.len(ProfileKind::User), 1);
+        assert_eq!(
+            batcher.take(ProfileKind::User, 60, 0),
+            vec![json!({"user_id": "u1", "metadata": {"plan": "pro"}})]
+        );
+        assert_eq!(batcher.len(ProfileKind::Company), 1);
+
+        // unchanged within the ttl
+        batcher.add(update(ProfileKind::User, "u1", "pro"), 60, 30);
+        assert!(batcher.take(ProfileKind::User, 60, 30).is_empty());
+        // changed
+        batcher.add(update(ProfileKind::User, "u1", "team"), 60, 30);
+        assert_eq!(batcher.take(ProfileKind::User, 60, 30).len(), 1);
+        // expired
+        batcher.add(update(ProfileKind::User, "u1", "team"), 60, 90);
+        assert_eq!(batcher.take(ProfileKind::User, 60, 90).len(), 1);
+    }
+}
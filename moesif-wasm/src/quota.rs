@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::event::Event;
+use crate::host::{read_slot_entry, update_slot_entry, Host};
+
+// Shared data key prefix of the usage counters
+const COUNTER_KEY_PREFIX: &str = "moesif_quota";
+
+fn default_status() -> u32 {
+    429
+}
+
+// The `quota` section of the plugin configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    // status of the response to over quota requests, 402 or 429
+    #[serde(default = "default_status")]
+    pub status: u32,
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> QuotaConfig {
+        QuotaConfig {
+            status: default_status(),
+            quotas: Vec::new(),
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.status != 402 && self.status != 429 {
+            errors.push(format!(
+                "quota.status must be 402 or 429, got {}",
+                self.status
+            ));
+        }
+        for (i, quota) in self.quotas.iter().enumerate() {
+            if quota.name.trim().is_empty() {
+                errors.push(format!("quota.quotas[{}].name must not be empty", i));
+            } else if self.quotas[..i].iter().any(|q| q.name == quota.name) {
+                errors.push(format!(
+                    "quota.quotas[{}].name {:?} is used by another quota",
+                    i, quota.name
+                ));
+            }
+            if quota.limit.is_none() && quota.limits.is_empty() {
+                errors.push(format!("quota.quotas[{}] needs a limit or limits", i));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaEntity {
+    User,
+    Company,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Minute,
+    Hour,
+    Day,
+    Month,
+}
+
+// A request quota of users or companies, an entry of quota.quotas
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quota {
+    pub name: String,
+    pub entity: QuotaEntity,
+    pub period: QuotaPeriod,
+    // limit of every user or company without their own entry in limits
+    pub limit: Option<u64>,
+    #[serde(default)]
+    pub limits: HashMap<String, u64>,
+}
+
+// The quota a request went over and when its period ends
+#[derive(Debug, PartialEq)]
+pub struct ExceededQuota<'a> {
+    pub name: &'a str,
+    pub retry_after: i64,
+}
+
+impl Quota {
+    fn entity_limit<'e>(&self, event: &'e Event) -> Option<(&'e str, u64)> {
+        let id = match self.entity {
+            QuotaEntity::User => event.user_id.as_deref()?,
+            QuotaEntity::Company => event.company_id.as_deref()?,
+        };
+        let limit = self.limits.get(id).copied().or(self.limit)?;
+        Some((id, limit))
+    }
+
+    // The unix times the period containing now starts and ends at
+    fn period_bounds(&self, now: DateTime<Utc>) -> (i64, i64) {
+        let seconds = match self.period {
+            QuotaPeriod::Minute => 60,
+            QuotaPeriod::Hour => 60 * 60,
+            QuotaPeriod::Day => 24 * 60 * 60,
+            QuotaPeriod::Month => {
+                let start = Utc
+                    .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+                    .unwrap();
+                let (year, month) = if now.month() == 12 {
+                    (now.year() + 1, 1)
+                } else {
+                    (now.year(), now.month() + 1)
+                };
+                let end = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap();
+                return (start.timestamp(), end.timestamp());
+            }
+        };
+        let start = now.timestamp() - now.timestamp().rem_euclid(seconds);
+        (start, start + seconds)
+    }
+
+    fn counter_prefix(&self) -> String {
+        format!("{}:{}", COUNTER_KEY_PREFIX, self.name)
+    }
+
+    // The seconds until the period ends when the request's user or company has
+    // used up the quota. Requests without the entity are never over it.
+    fn exceeded(&self, event: &Event, host: &dyn Host, now: DateTime<Utc>) -> Option<i64> {
+        let (id, limit) = self.entity_limit(event)?;
+        let (start, end) = self.period_bounds(now);
+        let (counter_start, count, _): (i64, u64, u64) =
+            read_slot_entry(host, &self.counter_prefix(), id)?;
+        if counter_start == start && count >= limit {
+            Some((end - now.timestamp()).max(1))
+        } else {
+            None
+        }
+    }
+
+    // Counts the request against the quota of its user or company
+    fn consume(&self, event: &Event, host: &dyn Host, now: DateTime<Utc>) {
+        if let Some((id, limit)) = self.entity_limit(event) {
+            let (start, _) = self.period_bounds(now);
+            increment_counter(host, &self.counter_prefix(), id, start, limit);
+        }
+    }
+}
+
+// The first quota the request's user or company is over, without counting the
+// request, so a request blocked by one quota doesn't use up the others
+pub fn exceeded_quota<'a>(
+    quotas: &'a [Quota],
+    event: &Event,
+    host: &dyn Host,
+) -> Option<ExceededQuota<'a>> {
+    let now = DateTime::<Utc>::from(host.get_current_time());
+    quotas.iter().find_map(|quota| {
+        quota
+            .exceeded(event, host, now)
+            .map(|retry_after| ExceededQuota {
+                name: &quota.name,
+                retry_after,
+            })
+    })
+}
+
+// Counts an allowed request against every quota
+pub fn count_quotas(quotas: &[Quota], event: &Event, host: &dyn Host) {
+    let now = DateTime::<Utc>::from(host.get_current_time());
+    for quota in quotas {
+        quota.consume(event, host, now);
+    }
+}
+
+// A counter is the start of its period, the count in it and its limit, counters
+// of earlier periods are dropped. The count stops at the limit. Counters at their
+// limit are evicted last, so ids over their quota stay blocked. Shared data errors
+// are logged, the request isn't counted.
+fn increment_counter(host: &dyn Host, prefix: &str, id: &str, period_start: i64, limit: u64) {
+    let result = update_slot_entry(
+        host,
+        prefix,
+        id,
+        |(start, _, _): &(i64, u64, u64)| *start != period_start,
+        |(_, count, limit)| count >= limit,
+        |counter| {
+            let count = counter.map_or(0, |(_, count, _)| *count);
+            Some((period_start, (count + 1).min(limit), limit))
+        },
+    );
+    if let Err(e) = result {
+        log::error!("Failed to update quota counter {} of {}: {:?}", prefix, id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::slot_key;
+    use crate::mock_host::MockHost;
+    use std::time::{Duration, SystemTime};
+
+    fn quotas() -> Vec<Quota> {
+        let config: QuotaConfig = serde_json::from_value(serde_json::json!({
+            "quotas": [
+                {"name": "api-calls", "entity": "user", "period": "month", "limit": 2, "limits": {"vip": 3}},
+                {"name": "burst", "entity": "company", "period": "minute", "limits": {"acme": 1}}
+            ]
+        }))
+        .unwrap();
+        let mut errors = Vec::new();
+        config.validate(&mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+        config.quotas
+    }
+
+    fn event(user_id: Option<&str>, company_id: Option<&str>) -> Event {
+        Event {
+            user_id: user_id.map(String::from),
+            company_id: company_id.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validates_quotas_and_period_bounds() {
+        let quotas = quotas();
+        let invalid: QuotaConfig = serde_json::from_value(serde_json::json!({
+            "status": 403,
+            "quotas": [
+                {"name": "a", "entity": "user", "period": "day", "limit": 1},
+                {"name": "a", "entity": "user", "period": "day"}
+            ]
+        }))
+        .unwrap();
+        let mut errors = Vec::new();
+        invalid.validate(&mut errors);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+
+        // 2024-12-31T23:59:30Z
+        let now =
+            DateTime::<Utc>::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_735_689_570));
+        assert_eq!(quotas[0].period_bounds(now), (1_733_011_200, 1_735_689_600));
+        assert_eq!(quotas[1].period_bounds(now), (1_735_689_540, 1_735_689_600));
+    }
+
+    // Checks the quotas and counts the request when it is allowed, as enforce does
+    fn request<'a>(quotas: &'a [Quota], event: &Event, host: &MockHost) -> Option<ExceededQuota<'a>> {
+        let exceeded = exceeded_quota(quotas, event, host);
+        if exceeded.is_none() {
+            count_quotas(quotas, event, host);
+        }
+        exceeded
+    }
+
+    #[test]
+    fn counts_requests_until_the_limit() {
+        let quotas = quotas();
+        let host = MockHost::new();
+        // 2024-01-02T03:04:05Z
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+
+        let user = event(Some("user-1"), None);
+        assert_eq!(request(&quotas, &user, &host), None);
+        assert_eq!(request(&quotas, &user, &host), None);
+        let exceeded = request(&quotas, &user, &host).unwrap();
+        assert_eq!(exceeded.name, "api-calls");
+        assert_eq!(exceeded.retry_after, 1_706_745_600 - 1_704_164_645);
+
+        let vip = event(Some("vip"), None);
+        for _ in 0..3 {
+            assert_eq!(request(&quotas, &vip, &host), None);
+        }
+        assert!(request(&quotas, &vip, &host).is_some());
+
+        // companies without a limit and unidentified requests aren't counted
+        assert_eq!(
+            request(&quotas, &event(None, Some("other")), &host),
+            None
+        );
+        assert_eq!(
+            request(&quotas, &event(None, Some("other")), &host),
+            None
+        );
+        assert_eq!(
+            request(&quotas, &event(None, Some("acme")), &host),
+            None
+        );
+        assert_eq!(
+            request(&quotas, &event(None, Some("acme")), &host).map(|e| e.retry_after),
+            Some(55)
+        );
+
+        // the count starts over in the next period
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_700));
+        assert_eq!(
+            request(&quotas, &event(None, Some("acme")), &host),
+            None
+        );
+
+        // counters of many ids share a fixed number of keys
+        let keys = host.shared_data_len();
+        for i in 0..5000 {
+            let user_id = format!("user-{}", i);
+            request(&quotas, &event(Some(&user_id), None), &host);
+        }
+        assert!(host.shared_data_len() <= keys + 1024);
+        assert!(request(&quotas, &event(Some("user-4999"), None), &host).is_none());
+        assert!(request(&quotas, &event(Some("user-4999"), None), &host).is_some());
+    }
+
+    #[test]
+    fn ids_over_their_quota_are_evicted_last() {
+        let quotas = quotas();
+        let host = MockHost::new();
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+        let over = event(Some("over"), None);
+        for _ in 0..2 {
+            assert_eq!(request(&quotas, &over, &host), None);
+        }
+        assert!(request(&quotas, &over, &host).is_some());
+
+        // fill the slot of "over" past its size with ids under their quota
+        let prefix = format!("{}:api-calls", COUNTER_KEY_PREFIX);
+        let slot = slot_key(&prefix, "over");
+        let ids: Vec<String> = (0..)
+            .map(|i| format!("user-{}", i))
+            .filter(|id| slot_key(&prefix, id) == slot)
+            .take(40)
+            .collect();
+        for id in &ids {
+            assert_eq!(request(&quotas, &event(Some(id), None), &host), None);
+        }
+        assert!(request(&quotas, &over, &host).is_some());
+    }
+
+    #[test]
+    fn blocked_requests_use_up_no_quota() {
+        let quotas = quotas();
+        let host = MockHost::new();
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+        let both = event(Some("user-1"), Some("acme"));
+        assert_eq!(request(&quotas, &both, &host), None);
+        assert_eq!(request(&quotas, &both, &host).unwrap().name, "burst");
+        // the request burst blocked wasn't counted against api-calls
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_164_700));
+        assert_eq!(request(&quotas, &both, &host), None);
+        assert_eq!(request(&quotas, &both, &host).unwrap().name, "api-calls");
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::event::Event;
+use crate::host::{read_slot_entry, update_slot_entry, Host};
+
+// Shared data key prefix of the token buckets
+const BUCKET_KEY_PREFIX: &str = "moesif_rate_limit";
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitPeriod {
+    #[default]
+    Second,
+    Minute,
+    Hour,
+}
+
+impl RateLimitPeriod {
+    fn seconds(self) -> f64 {
+        match self {
+            RateLimitPeriod::Second => 1.0,
+            RateLimitPeriod::Minute => 60.0,
+            RateLimitPeriod::Hour => 3600.0,
+        }
+    }
+}
+
+// A token bucket refilled with `limit` tokens every period that holds at most
+// `burst` tokens, limit by default. Each request takes a token.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub limit: u32,
+    #[serde(default)]
+    pub period: RateLimitPeriod,
+    pub burst: Option<u32>,
+}
+
+impl RateLimit {
+    fn validate(&self, field: &str, errors: &mut Vec<String>) {
+        if self.limit == 0 {
+            errors.push(format!("{}.limit must be greater than 0", field));
+        }
+        if self.burst == Some(0) {
+            errors.push(format!("{}.burst must be greater than 0", field));
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.limit) as f64
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.limit as f64 / self.period.seconds()
+    }
+}
+
+// The limits of each identity type, a request is limited by both
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub user: Option<RateLimit>,
+    pub company: Option<RateLimit>,
+}
+
+// The `rate_limits` section of the plugin configuration. A route selects one of
+// `routes` by name with its `rate_limit` metadata, identity types that entry
+// doesn't limit use the defaults.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub user: Option<RateLimit>,
+    pub company: Option<RateLimit>,
+    #[serde(default)]
+    pub routes: HashMap<String, RateLimits>,
+}
+
+// The bucket a request took a token from that has the fewest left, or the one it
+// couldn't take a token from
+#[derive(Debug, PartialEq)]
+pub struct RateLimitStatus {
+    // the Event.blocked_by of a request that was over the limit
+    pub blocked_by: Option<String>,
+    pub limit: u32,
+    pub remaining: u64,
+    // seconds until the bucket is full again
+    pub reset: u64,
+    // seconds until the next token, for blocked requests
+    pub retry_after: u64,
+}
+
+impl RateLimitStatus {
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("x-ratelimit-limit".to_string(), self.limit.to_string()),
+            (
+                "x-ratelimit-remaining".to_string(),
+                self.remaining.to_string(),
+            ),
+            ("x-ratelimit-reset".to_string(), self.reset.to_string()),
+        ];
+        if self.blocked_by.is_some() {
+            headers.push(("retry-after".to_string(), self.retry_after.to_string()));
+        }
+        headers
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        let defaults = ("rate_limits".to_string(), &self.user, &self.company);
+        let routes = self.routes.iter().map(|(name, limits)| {
+            (
+                format!("rate_limits.routes.{}", name),
+                &limits.user,
+                &limits.company,
+            )
+        });
+        for (field, user, company) in std::iter::once(defaults).chain(routes) {
+            if let Some(user) = user {
+                user.validate(&format!("{}.user", field), errors);
+            }
+            if let Some(company) = company {
+                company.validate(&format!("{}.company", field), errors);
+            }
+        }
+    }
+
+    // The status of the first bucket of the request's user or company that has no
+    // token left, without taking one
+    pub fn check(
+        &self,
+        route: Option<&str>,
+        event: &Event,
+        host: &dyn Host,
+    ) -> Option<RateLimitStatus> {
+        let now = now_seconds(host);
+        self.buckets(route, event).into_iter().find_map(|(entity, prefix, id, limit)| {
+            let tokens = bucket_tokens(read_slot_entry(host, &prefix, id), limit, now);
+            if tokens < 1.0 {
+                Some(status(limit, tokens, false, entity))
+            } else {
+                None
+            }
+        })
+    }
+
+    // Takes a token for the request's user and company, once both buckets have one.
+    // Requests without a user or company aren't limited by its limit, and shared
+    // data errors let requests through.
+    pub fn take(
+        &self,
+        route: Option<&str>,
+        event: &Event,
+        host: &dyn Host,
+    ) -> Option<RateLimitStatus> {
+        // a request one bucket rejects doesn't use up a token of the other
+        if let Some(blocked) = self.check(route, event, host) {
+            return Some(blocked);
+        }
+        let now = now_seconds(host);
+        let mut result: Option<RateLimitStatus> = None;
+        for (entity, prefix, id, limit) in self.buckets(route, event) {
+            let status = match take_token(host, &prefix, id, limit, now) {
+                Some((tokens, taken)) => status(limit, tokens, taken, entity),
+                None => continue,
+            };
+            if status.blocked_by.is_some() {
+                return Some(status);
+            }
+            if result
+                .as_ref()
+                .map_or(true, |r| status.remaining < r.remaining)
+            {
+                result = Some(status);
+            }
+        }
+        result
+    }
+
+    // The entity, key prefix, id and limit of each bucket the request takes from
+    fn buckets<'a>(
+        &'a self,
+        route: Option<&'a str>,
+        event: &'a Event,
+    ) -> Vec<(&'static str, String, &'a str, &'a RateLimit)> {
+        let route_limits = route.and_then(|name| {
+            let limits = self.routes.get(name);
+            if limits.is_none() {
+                log::warn!("Route rate_limit {} is not in rate_limits.routes", name);
+            }
+            limits.map(|limits| (name, limits))
+        });
+        let ids = [
+            ("user", event.user_id.as_deref()),
+            ("company", event.company_id.as_deref()),
+        ];
+        let mut buckets = Vec::new();
+        for (entity, id) in ids.iter() {
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+            // buckets of route limits are separate from the default ones
+            let route_limit =
+                route_limits.and_then(|(name, limits)| Some((name, limits.get(entity)?)));
+            if let Some((route, limit)) = route_limit.or_else(|| Some(("", self.get(entity)?))) {
+                let prefix = format!("{}:{}:{}", BUCKET_KEY_PREFIX, route, entity);
+                buckets.push((*entity, prefix, *id, limit));
+            }
+        }
+        buckets
+    }
+
+    fn get(&self, entity: &str) -> Option<&RateLimit> {
+        match entity {
+            "user" => self.user.as_ref(),
+            _ => self.company.as_ref(),
+        }
+    }
+}
+
+impl RateLimits {
+    fn get(&self, entity: &str) -> Option<&RateLimit> {
+        match entity {
+            "user" => self.user.as_ref(),
+            _ => self.company.as_ref(),
+        }
+    }
+}
+
+fn status(limit: &RateLimit, tokens: f64, taken: bool, entity: &str) -> RateLimitStatus {
+    let rate = limit.tokens_per_second();
+    RateLimitStatus {
+        blocked_by: if taken {
+            None
+        } else {
+            Some(format!("{}_rate_limit", entity))
+        },
+        limit: limit.limit,
+        remaining: tokens.floor() as u64,
+        reset: ((limit.capacity() - tokens) / rate).ceil() as u64,
+        retry_after: ((1.0 - tokens) / rate).ceil().max(1.0) as u64,
+    }
+}
+
+fn now_seconds(host: &dyn Host) -> f64 {
+    host.get_current_time()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// A bucket is its tokens and the time they were counted at
+type Bucket = (f64, f64);
+
+// The tokens of a bucket at now, a missing bucket is full
+fn bucket_tokens(bucket: Option<Bucket>, limit: &RateLimit, now: f64) -> f64 {
+    match bucket {
+        Some((tokens, updated)) => {
+            let tokens = tokens + (now - updated).max(0.0) * limit.tokens_per_second();
+            tokens.min(limit.capacity())
+        }
+        None => limit.capacity(),
+    }
+}
+
+// Returns the tokens left and whether one was taken. Full buckets are dropped
+// from shared data, they are the same as missing ones.
+fn take_token(
+    host: &dyn Host,
+    prefix: &str,
+    id: &str,
+    limit: &RateLimit,
+    now: f64,
+) -> Option<(f64, bool)> {
+    let mut result = (0.0, false);
+    let update = update_slot_entry(
+        host,
+        prefix,
+        id,
+        |bucket: &Bucket| bucket_tokens(Some(*bucket), limit, now) >= limit.capacity(),
+        |_| false,
+        |bucket| {
+            let mut tokens = bucket_tokens(bucket.copied(), limit, now);
+            let taken = tokens >= 1.0;
+            if taken {
+                tokens -= 1.0;
+            }
+            result = (tokens, taken);
+            Some((tokens, now))
+        },
+    );
+    match update {
+        Ok(()) => Some(result),
+        Err(e) => {
+            log::error!(
+                "Failed to update rate limit bucket {} of {}: {:?}",
+                prefix,
+                id,
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_host::MockHost;
+    use std::time::{Duration, SystemTime};
+
+    fn event(user_id: Option<&str>, company_id: Option<&str>) -> Event {
+        Event {
+            user_id: user_id.map(String::from),
+            company_id: company_id.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn buckets_refill_and_routes_have_their_own() {
+        let config: RateLimitConfig = serde_json::from_str(
+            r#"{
+                "user": {"limit": 2, "period": "minute"},
+                "company": {"limit": 10, "burst": 5},
+                "routes": {"search": {"user": {"limit": 1, "period": "hour"}}}
+            }"#,
+        )
+        .unwrap();
+        let mut errors = Vec::new();
+        config.validate(&mut errors);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let host = MockHost::new();
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let user = event(Some("user-1"), Some("acme"));
+
+        // the user bucket has fewer tokens left than the company one
+        let first = config.take(None, &user, &host).unwrap();
+        assert_eq!((first.limit, first.remaining, first.reset), (2, 1, 30));
+        assert_eq!(config.take(None, &user, &host).unwrap().remaining, 0);
+        let blocked = config.take(None, &user, &host).unwrap();
+        assert_eq!(blocked.blocked_by.as_deref(), Some("user_rate_limit"));
+        assert_eq!(blocked.retry_after, 30);
+        assert_eq!(blocked.headers().len(), 4);
+
+        // a route limit uses its own bucket and the default company limit
+        let search = config.take(Some("search"), &user, &host).unwrap();
+        assert_eq!((search.blocked_by, search.limit), (None, 1));
+        assert_eq!(
+            config
+                .take(Some("search"), &user, &host)
+                .unwrap()
+                .blocked_by
+                .as_deref(),
+            Some("user_rate_limit")
+        );
+
+        host.set_current_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_030));
+        assert_eq!(config.take(None, &user, &host).unwrap().blocked_by, None);
+        assert_eq!(config.take(None, &event(None, None), &host), None);
+
+        // a company over its limit doesn't use up the tokens of its users
+        let company_only: RateLimitConfig = serde_json::from_str(
+            r#"{"user": {"limit": 1, "period": "hour"}, "company": {"limit": 1, "period": "hour"}}"#,
+        )
+        .unwrap();
+        let other = event(Some("user-2"), Some("globex"));
+        assert_eq!(
+            company_only
+                .take(None, &event(Some("user-3"), Some("globex")), &host)
+                .unwrap()
+                .blocked_by,
+            None
+        );
+        let blocked = company_only.take(None, &other, &host).unwrap();
+        assert_eq!(blocked.blocked_by.as_deref(), Some("company_rate_limit"));
+        let alone = event(Some("user-2"), None);
+        assert_eq!(
+            company_only.take(None, &alone, &host).unwrap().blocked_by,
+            None
+        );
+
+        // buckets of many ids share a fixed number of keys
+        let keys = host.shared_data_len();
+        for i in 0..5000 {
+            company_only.take(None, &event(Some(&format!("many-{}", i)), None), &host);
+        }
+        assert!(host.shared_data_len() <= keys + 1024);
+
+        let invalid: RateLimitConfig =
+            serde_json::from_str(r#"{"routes": {"a": {"company": {"limit": 0, "burst": 0}}}}"#)
+                .unwrap();
+        let mut errors = Vec::new();
+        invalid.validate(&mut errors);
+        assert_eq!(errors.len(), 2);
+    }
+}
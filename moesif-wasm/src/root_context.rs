@@ -2,23 +2,41 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use proxy_wasm::traits::{Context, HttpContext, RootContext};
 use proxy_wasm::types::{Bytes, ContextType};
 
-use crate::config::{AppConfigResponse, Config, EnvConfig};
+use crate::actions::ActionMatcher;
+use crate::bots::BotDetector;
+use crate::client_ip::ClientIpResolver;
+use crate::config::{AppConfigResponse, Config, Endpoint, EnvConfig};
+use crate::host::Host;
 use crate::http_callback::{get_header, Handler, HttpCallbackManager};
 use crate::http_context::EventHttpContext;
-use crate::rules::{template, GovernanceRule, GovernanceRulesResponse};
+use crate::identity::IdentityResolver;
+use crate::profiles::{ProfileBatcher, ProfileKind, ProfileUpdate};
+use crate::skip::SkipRules;
+use crate::rules::{GovernanceRule, GovernanceRulesResponse};
+use crate::update_manager::UpdateManager;
 
-const EVENT_QUEUE: &str = "moesif_event_queue";
+pub const EVENT_QUEUE: &str = "moesif_event_queue";
+const PROFILE_QUEUE: &str = "moesif_profile_queue";
+const ACTION_QUEUE: &str = "moesif_action_queue";
+// the application config and governance rules are refetched at least this often,
+// and sooner when an event batch response reports a new etag
+const CONFIG_TTL_SECONDS: i64 = 300;
 
-#[derive(Default)]
 pub struct EventRootContext {
+    host: Arc<dyn Host>,
     context_id: String,
     config: Arc<Config>,
+    vm_variables: HashMap<String, String>,
     is_start: bool,
     event_byte_buffer: Arc<Mutex<Vec<Bytes>>>,
+    action_byte_buffer: Mutex<Vec<Bytes>>,
+    profiles: Mutex<ProfileBatcher>,
+    app_config: Arc<Mutex<UpdateManager<AppConfigResponse>>>,
+    governance_rules: Arc<Mutex<UpdateManager<GovernanceRulesResponse>>>,
     http_manager: HttpCallbackManager,
 }
 
@@ -39,8 +57,8 @@ impl Context for EventRootContext {
         );
 
         // To access the headers, body, and trailers
-        let headers = self.get_http_call_response_headers();
-        let body = self.get_http_call_response_body(0, body_size);
+        let headers = self.host.get_http_call_response_headers();
+        let body = self.host.get_http_call_response_body(0, body_size);
 
         self.http_manager.handle_response(token_id, headers, body);
     }
@@ -49,43 +67,67 @@ impl Context for EventRootContext {
 impl RootContext for EventRootContext {
     fn on_vm_start(&mut self, _: usize) -> bool {
         self.context_id = uuid::Uuid::new_v4().to_string();
-        self.set_tick_period(Duration::from_millis(1));
+        self.host.set_tick_period(Duration::from_millis(1));
         self.is_start = true;
-        let config = self.get_vm_configuration();
-        log::info!("VM configuration: {:?}", config);
+        // The VM configuration is only readable during on_vm_start, so keep any
+        // variables it defines for ${NAME} substitution in the plugin configuration
+        if let Some(vm_config) = self.host.get_vm_configuration() {
+            match serde_json::from_slice::<HashMap<String, String>>(&vm_config) {
+                Ok(variables) => self.vm_variables = variables,
+                Err(e) => log::debug!("VM configuration defines no variables: {:?}", e),
+            }
+        }
         true
     }
 
     fn on_configure(&mut self, _: usize) -> bool {
-        if let Some(config_bytes) = self.get_plugin_configuration() {
-            let config_str = std::str::from_utf8(&config_bytes).unwrap();
-            match serde_json::from_str::<EnvConfig>(config_str) {
-                Ok(env) => {
-                    let config = Config {
-                        env,
-                        event_queue_id: self.register_shared_queue(EVENT_QUEUE),
-                    };
-                    self.config = Arc::new(config);
-                    log::info!(
-                        "Loaded Moesif Application ID: {:?}",
-                        self.config.env.moesif_application_id
-                    );
-                    if self.config.env.debug {
-                        log::set_max_level(log::LevelFilter::Debug);
-                    } else {
-                        log::set_max_level(log::LevelFilter::Warn);
-                    }
-                    true
+        let config_bytes = match self.host.get_plugin_configuration() {
+            Some(config_bytes) => config_bytes,
+            None => {
+                log::error!("Failed to read configuration");
+                return false;
+            }
+        };
+        // ${NAME} is resolved from the VM configuration first, then from the
+        // environment variables Envoy exposes to the Wasm VM
+        let lookup = |name: &str| {
+            self.vm_variables
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+        };
+        match EnvConfig::from_bytes(&config_bytes, lookup) {
+            Ok(env) => {
+                let config = Config {
+                    skip: SkipRules::new(&env.skip),
+                    bots: BotDetector::new(&env.bots),
+                    client_ip: ClientIpResolver::new(&env.client_ip),
+                    identity: IdentityResolver::new(&env.identity),
+                    actions: ActionMatcher::new(&env.actions),
+                    env,
+                    event_queue_id: self.host.register_shared_queue(EVENT_QUEUE),
+                    profile_queue_id: self.host.register_shared_queue(PROFILE_QUEUE),
+                    action_queue_id: self.host.register_shared_queue(ACTION_QUEUE),
+                };
+                self.config = Arc::new(config);
+                log::info!(
+                    "Loaded Moesif Application ID: {:?}",
+                    self.config.env.moesif_application_id
+                );
+                if self.config.env.debug {
+                    log::set_max_level(log::LevelFilter::Debug);
+                } else {
+                    log::set_max_level(log::LevelFilter::Warn);
                 }
-                Err(e) => {
-                    // This will also catch the error when moesif_application_id is missing
-                    log::error!("Failed to parse configuration: {:?}", e);
-                    false
+                true
+            }
+            Err(errors) => {
+                // parse errors and every failed check, such as a missing application id
+                for error in errors {
+                    log::error!("Invalid configuration: {}", error);
                 }
+                false
             }
-        } else {
-            log::error!("Failed to read configuration");
-            false
         }
     }
 
@@ -93,7 +135,7 @@ impl RootContext for EventRootContext {
         log::trace!(
             "on_tick context_id {} at {}",
             self.context_id,
-            Utc::now().to_rfc3339()
+            DateTime::<Utc>::from(self.host.get_current_time()).to_rfc3339()
         );
         // We set on_tick to 1ms at start up to work around a bug or limitation in Envoy
         // where dispatch http call does not work in on_configure or on_vm_start.
@@ -101,28 +143,43 @@ impl RootContext for EventRootContext {
         if self.is_start {
             log::debug!("on_tick: first tick after on_configure");
             self.is_start = false;
-            self.set_tick_period(Duration::from_millis(self.config.env.batch_max_wait as u64));
-            // these init calls aren't required until governance rule support is added
-            // self.request_config_api();
-            // self.request_rules_api();
+            self.host.set_tick_period(Duration::from_millis(self.config.env.batch_max_wait as u64));
         }
-        self.poll_queue();
+        self.refresh_app_config();
+        self.refresh_governance_rules();
+        self.poll_queue(self.config.event_queue_id, &self.event_byte_buffer);
         // This will send all events in the buffer to enforce the batch_max_wait
         self.drain_and_send(1);
+        self.poll_queue(self.config.action_queue_id, &self.action_byte_buffer);
+        self.drain_and_send_actions(1);
+        self.poll_profile_queue();
+        self.send_profiles(1);
     }
 
-    fn on_queue_ready(&mut self, _queue_id: u32) {
-        log::trace!("on_queue_ready: {}", _queue_id);
-        self.poll_queue();
+    fn on_queue_ready(&mut self, queue_id: u32) {
+        log::trace!("on_queue_ready: {}", queue_id);
+        if queue_id == self.config.profile_queue_id {
+            self.poll_profile_queue();
+            self.send_profiles(self.config.env.batch_max_size);
+            return;
+        }
+        if queue_id == self.config.action_queue_id {
+            self.poll_queue(queue_id, &self.action_byte_buffer);
+            self.drain_and_send_actions(self.config.env.batch_max_size);
+            return;
+        }
+        self.poll_queue(self.config.event_queue_id, &self.event_byte_buffer);
         // This will send all full batches in the buffer to enforce the batch_max_size
         self.drain_and_send(self.config.env.batch_max_size);
     }
 
     fn create_http_context(&self, _: u32) -> Option<Box<dyn HttpContext>> {
-        Some(Box::new(EventHttpContext {
-            config: Arc::clone(&self.config),
-            ..Default::default()
-        }))
+        Some(Box::new(EventHttpContext::new(
+            Arc::clone(&self.config),
+            Arc::clone(&self.app_config),
+            Arc::clone(&self.governance_rules),
+            Arc::clone(&self.host),
+        )))
     }
 
     fn get_type(&self) -> Option<ContextType> {
@@ -131,13 +188,29 @@ impl RootContext for EventRootContext {
 }
 
 impl EventRootContext {
-    // dequeue all events and add them to the buffer until the queue is empty
-    fn poll_queue(&self) {
+    pub fn new(host: Arc<dyn Host>) -> EventRootContext {
+        EventRootContext {
+            host,
+            context_id: String::new(),
+            config: Arc::default(),
+            vm_variables: HashMap::new(),
+            is_start: false,
+            event_byte_buffer: Arc::default(),
+            action_byte_buffer: Mutex::default(),
+            profiles: Mutex::default(),
+            app_config: Arc::new(Mutex::new(UpdateManager::new(AppConfigResponse::new()))),
+            governance_rules: Arc::new(Mutex::new(UpdateManager::new(GovernanceRulesResponse::default()))),
+            http_manager: HttpCallbackManager::default(),
+        }
+    }
+
+    // dequeue all events or actions and add them to the buffer until the queue is empty
+    fn poll_queue(&self, queue_id: u32, buffer: &Mutex<Vec<Bytes>>) {
         let mut more = true;
         while more {
-            match self.dequeue_shared_queue(self.config.event_queue_id) {
+            match self.host.dequeue_shared_queue(queue_id) {
                 Ok(Some(event_bytes)) => {
-                    self.add_event(event_bytes);
+                    buffer.lock().unwrap().push(event_bytes);
                 }
                 Ok(None) => {
                     more = false;
@@ -150,34 +223,100 @@ impl EventRootContext {
         }
     }
 
-    fn add_event(&self, event_bytes: Bytes) {
-        let mut buffer: MutexGuard<Vec<Bytes>> = self.event_byte_buffer.lock().unwrap();
-        buffer.push(event_bytes);
+    fn drain_and_send(&self, drain_at_least: usize) {
+        self.send_batches(&self.event_byte_buffer, drain_at_least, Endpoint::Events, || {
+            let app_config = Arc::clone(&self.app_config);
+            let governance_rules = Arc::clone(&self.governance_rules);
+            Box::new(move |headers, _| {
+                let config_etag = get_header(&headers, "X-Moesif-Config-Etag");
+                let rules_etag = get_header(&headers, "X-Moesif-Rules-Etag");
+                log::info!(
+                    "Event Response eTags: config={:?} rules={:?}",
+                    config_etag,
+                    rules_etag
+                );
+                // the new config and rules are fetched on the next tick
+                if let Some(config_etag) = config_etag {
+                    app_config.lock().unwrap().notify_etag(&config_etag);
+                }
+                if let Some(rules_etag) = rules_etag {
+                    governance_rules.lock().unwrap().notify_etag(&rules_etag);
+                }
+            })
+        });
     }
 
-    fn drain_and_send(&self, drain_at_least: usize) {
-        let mut buffer: MutexGuard<Vec<Bytes>> = self.event_byte_buffer.lock().unwrap();
+    fn drain_and_send_actions(&self, drain_at_least: usize) {
+        self.send_batches(&self.action_byte_buffer, drain_at_least, Endpoint::Actions, || {
+            Box::new(|headers, _| {
+                let status = get_header(&headers, ":status");
+                log::info!("Action Response status {:?}", status);
+            })
+        });
+    }
+
+    // POSTs the buffered JSON values to endpoint in batches of batch_max_size while at
+    // least drain_at_least are buffered, each with a new callback from handler
+    fn send_batches<F>(&self, buffer: &Mutex<Vec<Bytes>>, drain_at_least: usize, endpoint: Endpoint, handler: F)
+    where
+        F: Fn() -> Handler,
+    {
+        let mut buffer: MutexGuard<Vec<Bytes>> = buffer.lock().unwrap();
         while buffer.len() >= drain_at_least {
             let end = std::cmp::min(buffer.len(), self.config.env.batch_max_size);
             let body = self.write_events_json(buffer.drain(..end).collect());
-            self.dispatch_http_request(
-                "POST",
-                "/v1/events/batch",
-                body,
-                Box::new(|headers, _| {
-                    let config_etag = get_header(&headers, "X-Moesif-Config-Etag");
-                    let rules_etag = get_header(&headers, "X-Moesif-Rules-Etag");
-                    log::info!(
-                        "Event Response eTags: config={:?} rules={:?}",
-                        config_etag,
-                        rules_etag
-                    );
-                }),
-            );
+            self.dispatch_http_request("POST", endpoint, body, handler());
+        }
+    }
+
+    // dequeue all profile updates into the batcher until the queue is empty
+    fn poll_profile_queue(&self) {
+        let ttl_seconds = self.config.env.profiles.ttl_seconds;
+        let now = self.now_timestamp();
+        let mut profiles = self.profiles.lock().unwrap();
+        loop {
+            match self.host.dequeue_shared_queue(self.config.profile_queue_id) {
+                Ok(Some(update_bytes)) => match serde_json::from_slice::<ProfileUpdate>(&update_bytes) {
+                    Ok(update) => profiles.add(update, ttl_seconds, now),
+                    Err(e) => log::error!("Failed to parse profile update: {:?}", e),
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Failed to dequeue profile update: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Sends the pending profiles of each kind once there are at least send_at_least
+    fn send_profiles(&self, send_at_least: usize) {
+        let ttl_seconds = self.config.env.profiles.ttl_seconds;
+        let now = self.now_timestamp();
+        for (kind, endpoint) in [(ProfileKind::User, Endpoint::Users), (ProfileKind::Company, Endpoint::Companies)] {
+            let batch = {
+                let mut profiles = self.profiles.lock().unwrap();
+                if profiles.len(kind) < send_at_least {
+                    continue;
+                }
+                profiles.take(kind, ttl_seconds, now)
+            };
+            for chunk in batch.chunks(self.config.env.batch_max_size) {
+                let body = serde_json::to_vec(chunk).unwrap();
+                self.dispatch_http_request(
+                    "POST",
+                    endpoint,
+                    body,
+                    Box::new(move |headers, _| {
+                        let status = get_header(&headers, ":status");
+                        log::info!("Profile Response {:?} status {:?}", endpoint, status);
+                    }),
+                );
+            }
         }
     }
 
-    // write vector of already serialized events as a JSON array
+    // write vector of already serialized events or actions as a JSON array
     fn write_events_json(&self, events: Vec<Bytes>) -> Bytes {
         // Calculate the total size of all event bytes
         let total_size: usize = events.iter().map(|event_bytes| event_bytes.len()).sum();
@@ -198,26 +337,59 @@ impl EventRootContext {
         event_json_array
     }
 
-    fn request_config_api(&self) {
+    fn now_timestamp(&self) -> i64 {
+        DateTime::<Utc>::from(self.host.get_current_time()).timestamp()
+    }
+
+    fn refresh_app_config(&self) {
+        let now = self.now_timestamp();
+        {
+            let mut app_config = self.app_config.lock().unwrap();
+            if !app_config.needs_refresh(CONFIG_TTL_SECONDS, now) {
+                return;
+            }
+            app_config.refresh_started(now);
+        }
+        self.request_config_api(now);
+    }
+
+    fn refresh_governance_rules(&self) {
+        let now = self.now_timestamp();
+        {
+            let mut governance_rules = self.governance_rules.lock().unwrap();
+            if !governance_rules.needs_refresh(CONFIG_TTL_SECONDS, now) {
+                return;
+            }
+            governance_rules.refresh_started(now);
+        }
+        self.request_rules_api(now);
+    }
+
+    fn request_config_api(&self, now: i64) {
+        let app_config = Arc::clone(&self.app_config);
         self.dispatch_http_request(
             "GET",
-            "/v1/config",
+            Endpoint::Config,
             Bytes::new(),
-            Box::new(|headers, body| {
+            Box::new(move |headers, body| {
                 let status = get_header(&headers, ":status").unwrap_or_default();
                 log::info!("Config Response status {:?}", status);
+                if status != "200" {
+                    log::warn!("Config request failed with status {:?}", status);
+                    return;
+                }
                 if let Some(body) = body {
-                    match serde_json::from_slice::<AppConfigResponse>(&body) {
+                    match AppConfigResponse::from_slice(&body) {
                         Ok(mut app_config_response) => {
                             log::info!("Config Response app_config_response: {:?}", app_config_response);
                             app_config_response.e_tag = get_header(&headers, "X-Moesif-Config-Etag");
+                            let etag = app_config_response.e_tag.clone().unwrap_or_default();
+                            app_config.lock().unwrap().update(etag, app_config_response, now);
                         }
                         Err(e) => {
                             log::error!("No valid AppConfigResponse: {:?}", e);
                         }
                     }
-                    // add error handling
-                    
                 } else {
                     log::warn!("Config Response body: None");
                 }
@@ -225,31 +397,27 @@ impl EventRootContext {
         );
     }
 
-    fn request_rules_api(&self) {
+    fn request_rules_api(&self, now: i64) {
+        let governance_rules = Arc::clone(&self.governance_rules);
         self.dispatch_http_request(
             "GET",
-            "/v1/rules",
+            Endpoint::Rules,
             Bytes::new(),
-            Box::new(|headers, body| {
-                let e_tag = get_header(&headers, "X-Moesif-Config-Etag");
+            Box::new(move |headers, body| {
+                let e_tag = get_header(&headers, "X-Moesif-Rules-Etag");
                 let status = get_header(&headers, ":status");
                 log::info!("Rules Response status {:?} e_tag {:?}", status, e_tag);
+                if status.as_deref() != Some("200") {
+                    log::warn!("Rules request failed with status {:?}", status);
+                    return;
+                }
                 if let Some(body) = body {
                     // This will provide a defult empty vector if there is no rules response
                     let rules = serde_json::from_slice::<Vec<GovernanceRule>>(&body).unwrap_or_default();
-                    let rules_response = GovernanceRulesResponse { rules, e_tag };
+                    let rules_response = GovernanceRulesResponse::new(rules, e_tag);
                     log::info!("Rules Response rules_response: {:?}", rules_response);
-                    for rule in rules_response.rules {
-                        if let (Some(body), Some(variables)) = (rule.response.body, rule.variables)
-                        {
-                            let variables: HashMap<String, String> = variables
-                                .into_iter()
-                                .map(|variable| (variable.name, variable.path))
-                                .collect();
-                            let templated_body = template(&body.0, &variables);
-                            log::info!("Rule templated_body: {:?}", templated_body);
-                        }
-                    }
+                    let etag = rules_response.e_tag.clone().unwrap_or_default();
+                    governance_rules.lock().unwrap().update(etag, rules_response, now);
                 } else {
                     log::warn!("Rules Response body: None");
                 }
@@ -260,13 +428,15 @@ impl EventRootContext {
     fn dispatch_http_request(
         &self,
         method: &str,
-        path: &str,
+        endpoint: Endpoint,
         body: Bytes,
         callback: Handler,
     ) -> u32 {
+        let path = self.config.env.endpoint_path(endpoint);
+        let path = path.as_str();
         let content_length = body.len().to_string();
         let application_id = self.config.env.moesif_application_id.clone();
-        let headers = vec![
+        let mut headers = vec![
             (":method", method),
             (":path", path),
             (":authority", &self.config.env.base_uri),
@@ -275,6 +445,13 @@ impl EventRootContext {
             ("content-length", &content_length),
             ("x-moesif-application-id", &application_id),
         ];
+        headers.extend(
+            self.config
+                .env
+                .upstream_headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
         let trailers = vec![];
         let timeout = Duration::from_millis(self.config.env.connection_timeout as u64);
         // encode body as a string to print
@@ -287,7 +464,7 @@ impl EventRootContext {
             bodystr
         );
         // Dispatch the HTTP request. The result is a token that uniquely identifies this call
-        match self.dispatch_http_call(
+        match self.host.dispatch_http_call(
             &self.config.env.upstream,
             headers,
             Some(&body),
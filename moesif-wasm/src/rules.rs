@@ -1,14 +1,89 @@
 use std::{collections::HashMap, fmt};
 
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde::de::{self, Deserializer, MapAccess, Visitor};
-use crate::event::{RequestInfo, ResponseInfo};
+use crate::conditions::CompiledConditions;
+use crate::config::{AppConfigResponse, EntityRuleValues};
+use crate::event::{Event, ResponseInfo};
+use crate::host::Host;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default)]
 pub struct GovernanceRulesResponse {
     pub rules: Vec<GovernanceRule>,
     pub e_tag: Option<String>,
+    // regex_config of each rule, in the same order as rules
+    pub conditions: CompiledConditions,
+    // whether a condition or variable reads the request body, so rules have to wait
+    // for it
+    pub uses_request_body: bool,
+}
+
+impl GovernanceRulesResponse {
+    // The rules that apply to the event in priority order: regex rules, then company
+    // rules, then user rules, so merging them lets user rules override the rest.
+    // User and company rules apply to the entities the application config assigns
+    // them to, or with applied_to not_matching to every other entity.
+    pub fn applicable_rules<'a>(
+        &'a self,
+        event: &Event,
+        app_config: &'a AppConfigResponse,
+    ) -> Vec<RuleTemplate<'a>> {
+        let mut applicable: Vec<(u8, RuleTemplate)> = Vec::new();
+        let matches = self.conditions.matches(event);
+        for (rule, matched) in self.rules.iter().zip(matches) {
+            if !matched {
+                continue;
+            }
+            let (priority, entity) = match rule.type_field.as_str() {
+                "regex" => (0, Some(None)),
+                "company" => (1, entity_rule(rule, event.company_id.as_ref(), &app_config.company_rules)),
+                "user" => (2, entity_rule(rule, event.user_id.as_ref(), &app_config.user_rules)),
+                other => {
+                    log::debug!("Ignoring rule {} with unknown type {}", rule.id, other);
+                    continue;
+                }
+            };
+            if let Some(entity_values) = entity {
+                applicable.push((priority, RuleTemplate::new(rule, event, entity_values)));
+            }
+        }
+        // a stable sort keeps the order of the rules response within a priority
+        applicable.sort_by_key(|(priority, _)| *priority);
+        applicable.into_iter().map(|(_, template)| template).collect()
+    }
+
+    // Rules are evaluated before the request is proxied, so response paths never
+    // have a value. Conditions on them are logged once here.
+    pub fn new(rules: Vec<GovernanceRule>, e_tag: Option<String>) -> GovernanceRulesResponse {
+        let mut uses_request_body = false;
+        for rule in &rules {
+            let conditions = rule.regex_config.iter().flat_map(|and| and.conditions.iter().map(|c| &c.path));
+            let variables = rule.variables.iter().flatten().map(|v| &v.path);
+            for path in conditions.clone().chain(variables) {
+                uses_request_body |= path.starts_with("request.body.");
+            }
+            for path in conditions.filter(|path| path.starts_with("response.")) {
+                log::warn!("Governance rule {} condition on {} never matches before the response", rule.id, path);
+            }
+        }
+        let entries = rules
+            .iter()
+            .map(|rule| {
+                let groups = rule
+                    .regex_config
+                    .iter()
+                    .map(|and| and.conditions.iter().map(|c| (c.path.as_str(), c.value.as_str())).collect())
+                    .collect();
+                (rule.id.clone(), groups)
+            })
+            .collect();
+        GovernanceRulesResponse {
+            conditions: CompiledConditions::compile(entries),
+            rules,
+            e_tag,
+            uses_request_body,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,6 +161,27 @@ impl<'de> Deserialize<'de> for BodyTemplate {
     }
 }
 
+// Whether a user or company rule applies to the entity, along with the variable
+// values its assignment provides
+fn entity_rule<'a>(
+    rule: &GovernanceRule,
+    entity_id: Option<&String>,
+    assignments: &'a HashMap<String, Vec<EntityRuleValues>>,
+) -> Option<Option<&'a HashMap<String, String>>> {
+    let entity_id = match entity_id {
+        Some(entity_id) => entity_id,
+        None => return if rule.applied_to_unidentified { Some(None) } else { None },
+    };
+    let assignment = assignments
+        .get(entity_id)
+        .and_then(|values| values.iter().find(|v| v.rules == rule.id));
+    match (rule.applied_to.as_str(), assignment) {
+        ("matching", Some(assignment)) => Some(assignment.values.as_ref()),
+        ("not_matching", None) => Some(None),
+        _ => None,
+    }
+}
+
 pub fn template(t: &str, vars: &HashMap<String, String>) -> String {
     let mut s = t.to_owned();
     for (name, value) in vars {
@@ -94,20 +190,60 @@ pub fn template(t: &str, vars: &HashMap<String, String>) -> String {
     s
 }
 
-pub struct RuleTemplate {
-    rule: GovernanceRule,
+// Response bodies are JSON, so values are escaped to stay valid inside the string
+// literals that hold the {{name}} placeholders
+pub fn template_json(t: &str, vars: &HashMap<String, String>) -> String {
+    let escaped = vars
+        .iter()
+        .map(|(name, value)| {
+            let quoted = serde_json::Value::String(value.clone()).to_string();
+            (name.clone(), quoted[1..quoted.len() - 1].to_string())
+        })
+        .collect();
+    template(t, &escaped)
+}
+
+pub struct RuleTemplate<'a> {
+    rule: &'a GovernanceRule,
     values: HashMap<String, String>,
 }
 
-impl RuleTemplate {
+impl<'a> RuleTemplate<'a> {
+    // Variables take the value a user or company rule assignment gives them and
+    // otherwise resolve their path against the request, unresolved variables are empty
+    pub fn new(
+        rule: &'a GovernanceRule,
+        event: &Event,
+        entity_values: Option<&HashMap<String, String>>,
+    ) -> RuleTemplate<'a> {
+        let values = rule
+            .variables
+            .iter()
+            .flatten()
+            .map(|variable| {
+                let value = entity_values
+                    .and_then(|values| values.get(&variable.name).cloned())
+                    .or_else(|| event.lookup(&variable.path))
+                    .unwrap_or_default();
+                (variable.name.clone(), value)
+            })
+            .collect();
+        RuleTemplate { rule, values }
+    }
+
     fn template_override(&self) -> TemplatedOverrideValues {
         let mut headers = HashMap::new();
         for (k, v) in &self.rule.response.headers {
             headers.insert(k.clone(), template(v, &self.values));
         }
-        let body = self.rule.response.body.as_ref().map(|b| template(&b.0, &self.values));
+        let body = self.rule.response.body.as_ref().map(|b| template_json(&b.0, &self.values));
         TemplatedOverrideValues {
             block: self.rule.block,
+            blocked_by: if self.rule.block {
+                Some(self.rule.id.clone())
+            } else {
+                None
+            },
             headers,
             status: self.rule.response.status,
             body: body.map(|b| b.into_bytes()),
@@ -117,6 +253,7 @@ impl RuleTemplate {
 
 pub struct TemplatedOverrideValues {
     block: bool,
+    blocked_by: Option<String>,
     headers: HashMap<String, String>,
     status: i32,
     body: Option<Vec<u8>>,
@@ -126,66 +263,280 @@ pub struct ResponseOverride {
     override_values: TemplatedOverrideValues,
     response: ResponseInfo,
     wrote_headers: bool,
-    wrote_body: bool,
 }
 
 impl ResponseOverride {
-    fn new(response: ResponseInfo, templates: Vec<RuleTemplate>) -> Self {
+    // Templates are merged in order, so later rules override the status, headers
+    // and body of earlier ones. Once a rule blocks, only later blocking rules
+    // replace the status and body of the blocked response.
+    pub fn new(response: ResponseInfo, templates: Vec<RuleTemplate>) -> Self {
         let mut override_values = TemplatedOverrideValues {
             block: false,
+            blocked_by: None,
             headers: HashMap::new(),
             status: 0,
             body: None,
         };
         for template in templates {
             let t = template.template_override();
+            if t.block && !override_values.block {
+                // the body of a non-blocking rule isn't part of the blocked response
+                override_values.body = None;
+            }
+            if t.block || !override_values.block {
+                override_values.status = t.status;
+                if let Some(body) = t.body {
+                    override_values.body = Some(body);
+                }
+            }
             override_values.block |= t.block;
-            override_values.status = t.status;
+            if t.blocked_by.is_some() {
+                override_values.blocked_by = t.blocked_by;
+            }
             for (k, v) in t.headers {
                 override_values.headers.insert(k, v);
             }
-            if let Some(body) = t.body {
-                override_values.body = Some(body);
-            }
         }
         Self {
             override_values,
             response,
             wrote_headers: false,
-            wrote_body: false,
         }
     }
-}
 
-fn check_regex(rule: &GovernanceRule, req: &RequestInfo) -> bool {
-    if rule.regex_config.is_empty() {
-        return true;
+    pub fn is_blocked(&self) -> bool {
+        self.override_values.block
     }
-    for regex_and in &rule.regex_config {
-        let and_value = regex_and.conditions.iter().all(|c| {
-            let s = request_path_lookup(&req, &c.path);
-            let re = Regex::new(&c.value);
-            match re {
-                Ok(re) => re.is_match(&s),
-                Err(_) => {
-                    eprintln!("Governance rule regex error: org-app={}-{} rule.id={} rule.name={} path={} regex={}", rule.org_id, rule.app_id, rule.id, rule.name, c.path, c.value);
-                    false
-                }
-            }
-        });
-        if and_value {
-            return true;
+
+    // The id of the highest priority blocking rule
+    pub fn blocked_by(&self) -> Option<&str> {
+        self.override_values.blocked_by.as_deref()
+    }
+
+    // Answers a blocked request with a local response instead of proxying it
+    pub fn send_blocked_response(&self, host: &dyn Host) {
+        let values = &self.override_values;
+        let headers = values
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        host.send_http_response(blocked_status(values.status), headers, values.body.as_deref());
+    }
+
+    // Sets the headers of non-blocking rules on a proxied response, and the rule
+    // status as well when override_status is set. Headers are only written once.
+    pub fn write_response_headers(&mut self, host: &dyn Host, override_status: bool) {
+        if self.wrote_headers {
+            return;
+        }
+        self.wrote_headers = true;
+        for (name, value) in &self.override_values.headers {
+            host.set_http_response_header(name, Some(value));
+        }
+        let status = self.override_values.status;
+        if override_status && (100..=599).contains(&status) {
+            host.set_http_response_header(":status", Some(&status.to_string()));
+        }
+    }
+
+    // The blocked response as it is logged to Moesif
+    pub fn into_blocked_response_info(self) -> ResponseInfo {
+        let values = self.override_values;
+        let body = match values.body {
+            Some(body) => serde_json::from_slice(&body)
+                .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())),
+            None => serde_json::Value::Null,
+        };
+        ResponseInfo {
+            status: blocked_status(values.status) as usize,
+            headers: values.headers.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect(),
+            body,
+            ..self.response
         }
     }
-    false
 }
 
-fn request_path_lookup(req: &RequestInfo, path: &str) -> String {
-    match path {
-        "request.uri" => req.uri.clone(),
-        "request.verb" => req.verb.clone(),
-        // Add more path cases based on your needs
-        _ => "".into(),
+// rules without a valid status block with 403
+fn blocked_status(status: i32) -> u32 {
+    if (100..=599).contains(&status) {
+        status as u32
+    } else {
+        403
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::RequestInfo;
+
+    fn rule(regex_config: &str) -> GovernanceRule {
+        serde_json::from_value(serde_json::json!({
+            "_id": "rule-1",
+            "name": "block",
+            "type": "regex",
+            "block": true,
+            "regex_config": serde_json::from_str::<serde_json::Value>(regex_config).unwrap(),
+            "response": {"status": 403, "headers": {}, "body": {"error": "{{reason}}"}},
+            "applied_to": "matching",
+            "applied_to_unidentified": false,
+            "org_id": "org",
+            "app_id": "app",
+            "created_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn template_replaces_variables() {
+        let vars: HashMap<String, String> = vec![("reason".to_string(), "quota".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(template(r#"{"error":"{{reason}}"}"#, &vars), r#"{"error":"quota"}"#);
+    }
+
+    #[test]
+    fn rules_response_compiles_the_regex_config_of_each_rule() {
+        let response = GovernanceRulesResponse::new(
+            vec![
+                rule(r#"[{"conditions": [{"path": "request.verb", "value": "POST"}, {"path": "request.uri", "value": "^/orders"}]}]"#),
+                rule(r#"[{"conditions": [{"path": "user_id", "value": ".*"}, {"path": "response.status", "value": "^4"}]}]"#),
+                rule("[]"),
+            ],
+            None,
+        );
+        let mut event = Event {
+            request: RequestInfo {
+                verb: "POST".to_string(),
+                uri: "/orders/1".to_string(),
+                ..Default::default()
+            },
+            response: Some(ResponseInfo {
+                status: 429,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(response.conditions.matches(&event), vec![true, false, true]);
+        event.user_id = Some("user-1".to_string());
+        assert_eq!(response.conditions.matches(&event), vec![true, true, true]);
+    }
+
+    #[test]
+    fn variables_resolve_from_entity_values_and_the_request() {
+        let mut rule = rule("[]");
+        rule.variables = Some(vec![
+            Variable {
+                name: "reason".to_string(),
+                path: "request.headers.x-reason".to_string(),
+            },
+            Variable {
+                name: "plan".to_string(),
+                path: "request.body.plan".to_string(),
+            },
+            Variable {
+                name: "missing".to_string(),
+                path: "company_id".to_string(),
+            },
+        ]);
+        rule.response.headers.insert("X-Plan".to_string(), "{{plan}}{{missing}}".to_string());
+        let event = Event {
+            request: RequestInfo {
+                headers: vec![("x-reason".to_string(), r#"over "quota""#.to_string())]
+                    .into_iter()
+                    .collect(),
+                body: serde_json::json!({"plan": "free"}),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let entity_values: HashMap<String, String> =
+            vec![("plan".to_string(), "gold".to_string())].into_iter().collect();
+
+        let values = RuleTemplate::new(&rule, &event, None).template_override();
+        assert_eq!(values.headers["X-Plan"], "free");
+        let body: serde_json::Value = serde_json::from_slice(&values.body.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({"error": r#"over "quota""#}));
+
+        let values = RuleTemplate::new(&rule, &event, Some(&entity_values)).template_override();
+        assert_eq!(values.headers["X-Plan"], "gold");
+    }
+
+    #[test]
+    fn later_non_blocking_rules_keep_the_blocked_status_and_body() {
+        let blocking = rule("[]");
+        let mut tagging = rule("[]");
+        tagging.id = "rule-2".to_string();
+        tagging.block = false;
+        tagging.response.status = 200;
+        tagging.response.body = Some(BodyTemplate(r#"{"note": "tagged"}"#.to_string()));
+        tagging.response.headers.insert("X-Tag".to_string(), "yes".to_string());
+        let event = Event::default();
+
+        let templates = vec![
+            RuleTemplate::new(&blocking, &event, None),
+            RuleTemplate::new(&tagging, &event, None),
+        ];
+        let response_override = ResponseOverride::new(ResponseInfo::default(), templates);
+        assert!(response_override.is_blocked());
+        assert_eq!(response_override.blocked_by(), Some("rule-1"));
+        let response = response_override.into_blocked_response_info();
+        assert_eq!(response.status, 403);
+        assert_eq!(response.body, serde_json::json!({"error": "{{reason}}"}));
+        assert_eq!(response.headers["x-tag"], "yes");
+
+        // a non-blocking rule before the blocking one doesn't add its body either
+        let templates = vec![
+            RuleTemplate::new(&tagging, &event, None),
+            RuleTemplate::new(&blocking, &event, None),
+        ];
+        let response = ResponseOverride::new(ResponseInfo::default(), templates).into_blocked_response_info();
+        assert_eq!(response.status, 403);
+        assert_eq!(response.body, serde_json::json!({"error": "{{reason}}"}));
+    }
+
+    #[test]
+    fn applicable_rules_follow_entity_assignments_in_priority_order() {
+        let rule_with = |id: &str, type_field: &str, applied_to: &str, unidentified: bool| {
+            let mut rule = rule("[]");
+            rule.id = id.to_string();
+            rule.type_field = type_field.to_string();
+            rule.applied_to = applied_to.to_string();
+            rule.applied_to_unidentified = unidentified;
+            rule
+        };
+        let response = GovernanceRulesResponse::new(
+            vec![
+                rule_with("user-not-in-cohort", "user", "not_matching", true),
+                rule_with("company-in-cohort", "company", "matching", false),
+                rule_with("everyone", "regex", "matching", false),
+                rule_with("user-in-cohort", "user", "matching", false),
+            ],
+            None,
+        );
+        let app_config: AppConfigResponse = serde_json::from_value(serde_json::json!({
+            "org_id": "org", "app_id": "app", "sample_rate": 100, "block_bot_traffic": false,
+            "user_sample_rate": {}, "company_sample_rate": {},
+            "user_rules": {"user-1": [{"rules": "user-in-cohort", "values": {"plan": "gold"}}, {"rules": "user-not-in-cohort"}]},
+            "company_rules": {"acme": [{"rules": "company-in-cohort"}]},
+            "ip_addresses_blocked_by_name": {}, "regex_config": [], "billing_config_jsons": {}
+        }))
+        .unwrap();
+        let applicable = |user_id: Option<&str>, company_id: Option<&str>| {
+            let event = Event {
+                user_id: user_id.map(String::from),
+                company_id: company_id.map(String::from),
+                ..Default::default()
+            };
+            response
+                .applicable_rules(&event, &app_config)
+                .iter()
+                .map(|t| t.rule.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(applicable(Some("user-1"), Some("acme")), vec!["everyone", "company-in-cohort", "user-in-cohort"]);
+        assert_eq!(applicable(Some("user-2"), Some("other")), vec!["everyone", "user-not-in-cohort"]);
+        assert_eq!(applicable(None, None), vec!["everyone", "user-not-in-cohort"]);
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::validate_header_name;
+use crate::event::RequestInfo;
+
+// The `skip` section of the plugin configuration. A request matching any of
+// these is never serialized or sent to Moesif.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct SkipConfig {
+    // matched against the start of the request URI
+    #[serde(default)]
+    pub path_prefixes: Vec<String>,
+    // matched against the full request URI including the query string
+    #[serde(default)]
+    pub path_regexes: Vec<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // header name -> regex matched against the header value
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub status_codes: Vec<usize>,
+}
+
+impl SkipConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        for pattern in &self.path_regexes {
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(format!(
+                    "skip.path_regexes {:?} is not a valid regex: {}",
+                    pattern, e
+                ));
+            }
+        }
+        for (name, pattern) in &self.headers {
+            validate_header_name("skip.headers", Some(name), errors);
+            if let Err(e) = Regex::new(pattern) {
+                errors.push(format!(
+                    "skip.headers.{} {:?} is not a valid regex: {}",
+                    name, pattern, e
+                ));
+            }
+        }
+        for status in &self.status_codes {
+            if !(100..=599).contains(status) {
+                errors.push(format!(
+                    "skip.status_codes {} is not a valid HTTP status",
+                    status
+                ));
+            }
+        }
+    }
+}
+
+// SkipConfig with its regexes compiled once at configuration time
+#[derive(Default, Clone)]
+pub struct SkipRules {
+    path_prefixes: Vec<String>,
+    path_regexes: Vec<Regex>,
+    methods: Vec<String>,
+    headers: Vec<(String, Regex)>,
+    status_codes: Vec<usize>,
+}
+
+impl SkipRules {
+    // Invalid regexes are dropped here, they are reported by SkipConfig::validate
+    pub fn new(config: &SkipConfig) -> SkipRules {
+        SkipRules {
+            path_prefixes: config.path_prefixes.clone(),
+            path_regexes: config
+                .path_regexes
+                .iter()
+                .filter_map(|pattern| Regex::new(pattern).ok())
+                .collect(),
+            methods: config.methods.iter().map(|m| m.to_uppercase()).collect(),
+            headers: config
+                .headers
+                .iter()
+                .filter_map(|(name, pattern)| {
+                    Regex::new(pattern).ok().map(|re| (name.to_lowercase(), re))
+                })
+                .collect(),
+            status_codes: config.status_codes.clone(),
+        }
+    }
+
+    // The request headers must already be lowercased
+    pub fn matches_request(&self, request: &RequestInfo) -> bool {
+        self.path_prefixes
+            .iter()
+            .any(|prefix| request.uri.starts_with(prefix.as_str()))
+            || self.path_regexes.iter().any(|re| re.is_match(&request.uri))
+            || self
+                .methods
+                .iter()
+                .any(|method| method.eq_ignore_ascii_case(&request.verb))
+            || self.headers.iter().any(|(name, re)| {
+                request
+                    .headers
+                    .get(name)
+                    .is_some_and(|value| re.is_match(value))
+            })
+    }
+
+    pub fn matches_status(&self, status: usize) -> bool {
+        self.status_codes.contains(&status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(verb: &str, uri: &str, headers: &[(&str, &str)]) -> RequestInfo {
+        RequestInfo {
+            verb: verb.to_string(),
+            uri: uri.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn skip_rules(config: &str) -> SkipRules {
+        SkipRules::new(&serde_json::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn matches_paths_methods_and_headers() {
+        let rules = skip_rules(
+            r#"{
+                "path_prefixes": ["/healthz"],
+                "path_regexes": ["^/internal/.*\\?debug"],
+                "methods": ["options"],
+                "headers": {"User-Agent": "^kube-probe/"}
+            }"#,
+        );
+        assert!(rules.matches_request(&request("GET", "/healthz/live", &[])));
+        assert!(rules.matches_request(&request("GET", "/internal/x?debug=1", &[])));
+        assert!(rules.matches_request(&request("OPTIONS", "/api", &[])));
+        assert!(rules.matches_request(&request(
+            "GET",
+            "/api",
+            &[("user-agent", "kube-probe/1.27")]
+        )));
+        assert!(!rules.matches_request(&request("GET", "/api", &[("user-agent", "curl/8.0")])));
+        assert!(!rules.matches_request(&request("GET", "/internal/x", &[])));
+    }
+
+    #[test]
+    fn matches_status_codes() {
+        let rules = skip_rules(r#"{"status_codes": [404]}"#);
+        assert!(rules.matches_status(404));
+        assert!(!rules.matches_status(200));
+    }
+
+    #[test]
+    fn validates_regexes_and_statuses() {
+        let config: SkipConfig =
+            serde_json::from_str(r#"{"path_regexes": ["("], "status_codes": [99]}"#).unwrap();
+        let mut errors = Vec::new();
+        config.validate(&mut errors);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+    }
+}
@@ -1,30 +1,58 @@
-use chrono::{DateTime, Utc};
-
-#[derive(Default)]
+// Holds data fetched from the Moesif API along with the etag it was fetched at.
+// Times are unix seconds from the host clock.
 pub struct UpdateManager<T> {
     current_etag: String,
     old_etag: String,
     last_updated: i64,
+    refresh_requested: bool,
     data: T,
 }
 
 impl<T> UpdateManager<T> {
-    fn update(&mut self, etag: String, data: T) {
+    pub fn new(data: T) -> UpdateManager<T> {
+        UpdateManager {
+            current_etag: String::new(),
+            old_etag: String::new(),
+            last_updated: 0,
+            // nothing has been fetched yet
+            refresh_requested: true,
+            data,
+        }
+    }
+
+    pub fn update(&mut self, etag: String, data: T, now: i64) {
         self.old_etag = self.current_etag.clone();
         self.current_etag = etag;
-        self.last_updated = Utc::now().timestamp();
+        self.last_updated = now;
         self.data = data;
     }
 
-    fn is_updated(&self, etag: &str) -> bool {
+    pub fn is_updated(&self, etag: &str) -> bool {
         self.current_etag != etag && self.old_etag != etag
     }
 
-    fn is_expired(&self, ttl: i64) -> bool {
-        self.last_updated + ttl < Utc::now().timestamp()
+    pub fn is_expired(&self, ttl: i64, now: i64) -> bool {
+        self.last_updated + ttl < now
+    }
+
+    // The collector reports the current etag on every event batch response
+    pub fn notify_etag(&mut self, etag: &str) {
+        if self.is_updated(etag) {
+            self.refresh_requested = true;
+        }
     }
 
-    fn get_data(&self) -> &T {
+    pub fn needs_refresh(&self, ttl: i64, now: i64) -> bool {
+        self.refresh_requested || self.is_expired(ttl, now)
+    }
+
+    // A failed fetch is retried once the ttl passes again
+    pub fn refresh_started(&mut self, now: i64) {
+        self.refresh_requested = false;
+        self.last_updated = now;
+    }
+
+    pub fn get_data(&self) -> &T {
         &self.data
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,28 @@
+use moesif_envoy_wasm_plugin::mock_host::MockHost;
+use moesif_envoy_wasm_plugin::root_context::EventRootContext;
+use proxy_wasm::traits::RootContext;
+
+// Drives one request through a new http context the way Envoy would, ending with on_log
+pub fn run_request(
+    host: &MockHost,
+    root: &EventRootContext,
+    request_headers: Vec<(&str, &str)>,
+    request_body: &[u8],
+    response_headers: Vec<(&str, &str)>,
+    response_body: &[u8],
+) {
+    let mut http = root.create_http_context(2).unwrap();
+    host.set_http_request_headers(request_headers);
+    http.on_http_request_headers(0, request_body.is_empty());
+    if !request_body.is_empty() {
+        host.set_http_request_body(request_body);
+        http.on_http_request_body(request_body.len(), true);
+    }
+    host.set_http_response_headers(response_headers);
+    http.on_http_response_headers(0, response_body.is_empty());
+    if !response_body.is_empty() {
+        host.set_http_response_body(response_body);
+        http.on_http_response_body(response_body.len(), true);
+    }
+    http.on_log();
+}
@@ -0,0 +1,253 @@
+// Replays recorded traffic through the filter against MockHost and writes the
+// resulting Moesif event batches to disk, along with the user and company
+// profile batches.
+//
+//   REPLAY_INPUT     JSONL file of recorded exchanges (tests/data/replay.jsonl)
+//   REPLAY_CONFIG    plugin configuration (tests/data/replay-config.json)
+//   REPLAY_OUTPUT    directory the batches are written to (target/tmp/replay)
+//   REPLAY_EXPECTED  directory of batches the output must match, defaults to
+//                    tests/data/replay-expected when REPLAY_INPUT is not set
+//
+// Each input line is one exchange:
+//
+//   {"time": "2024-01-02T03:04:05Z",
+//    "request": {"method": "POST", "path": "/orders", "headers": {...}, "body": ...},
+//    "response": {"status": 201, "headers": {...}, "body": ...},
+//    "route_metadata": {"sample_rate": 50}}
+//
+// A string body is sent as is and any other JSON body is serialized.
+mod common;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::DateTime;
+use common::run_request;
+use moesif_envoy_wasm_plugin::mock_host::MockHost;
+use moesif_envoy_wasm_plugin::root_context::{EventRootContext, EVENT_QUEUE};
+use proxy_wasm::traits::RootContext;
+use serde::Deserialize;
+use serde_json::Value;
+
+// Collector paths and the file names their batches are written to
+const BATCH_FILES: &[(&str, &str)] = &[
+    ("/v1/events/batch", "batch"),
+    ("/v1/users/batch", "users"),
+    ("/v1/companies/batch", "companies"),
+];
+
+#[derive(Deserialize)]
+struct Exchange {
+    time: Option<String>,
+    request: RecordedRequest,
+    response: RecordedResponse,
+    #[serde(default)]
+    route_metadata: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct RecordedRequest {
+    #[serde(default = "default_method")]
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Value,
+}
+
+#[derive(Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Value,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn body_bytes(body: &Value) -> Vec<u8> {
+    match body {
+        Value::Null => Vec::new(),
+        Value::String(s) => s.as_bytes().to_vec(),
+        other => serde_json::to_vec(other).unwrap(),
+    }
+}
+
+// Route metadata is encoded the way Envoy serializes struct fields to the plugin
+fn metadata_bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Bool(b) => vec![*b as u8],
+        Value::Number(n) => n.as_f64().unwrap_or_default().to_le_bytes().to_vec(),
+        Value::String(s) => s.as_bytes().to_vec(),
+        other => serde_json::to_vec(other).unwrap(),
+    }
+}
+
+fn replay(
+    host: &MockHost,
+    root: &mut EventRootContext,
+    exchange: Exchange,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(time) = &exchange.time {
+        host.set_current_time(SystemTime::from(DateTime::parse_from_rfc3339(time)?));
+    }
+    host.clear_properties();
+    for (name, value) in &exchange.route_metadata {
+        host.set_property(
+            &["route_metadata", "filter_metadata", "moesif", name],
+            &metadata_bytes(value),
+        );
+    }
+
+    let request = &exchange.request;
+    let mut request_headers = vec![
+        (":method", request.method.as_str()),
+        (":path", request.path.as_str()),
+    ];
+    request_headers.extend(
+        request
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())),
+    );
+    let status = exchange.response.status.to_string();
+    let mut response_headers = vec![(":status", status.as_str())];
+    response_headers.extend(
+        exchange
+            .response
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())),
+    );
+
+    run_request(
+        host,
+        root,
+        request_headers,
+        &body_bytes(&request.body),
+        response_headers,
+        &body_bytes(&exchange.response.body),
+    );
+    // Envoy signals the root context every time an event is enqueued
+    root.on_queue_ready(host.queue_id(EVENT_QUEUE).unwrap());
+    Ok(())
+}
+
+fn env_path(name: &str, default: &str) -> PathBuf {
+    env::var_os(name)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(default))
+}
+
+fn batch_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    files.sort();
+    Ok(files)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let input = env_path("REPLAY_INPUT", "tests/data/replay.jsonl");
+    let config = env_path("REPLAY_CONFIG", "tests/data/replay-config.json");
+    let output = env::var_os("REPLAY_OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_TARGET_TMPDIR")).join("replay"));
+    let expected = match env::var_os("REPLAY_EXPECTED") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None if env::var_os("REPLAY_INPUT").is_none() => {
+            Some(PathBuf::from("tests/data/replay-expected"))
+        }
+        None => None,
+    };
+
+    let host = Arc::new(MockHost::new());
+    host.set_plugin_configuration(&fs::read_to_string(&config)?);
+    let mut root = EventRootContext::new(host.clone());
+    root.on_vm_start(0);
+    if !root.on_configure(0) {
+        return Err(format!("{} is not a valid configuration", config.display()).into());
+    }
+    root.on_tick();
+
+    let mut replayed = 0;
+    for (i, line) in BufReader::new(fs::File::open(&input)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Exchange = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {}", input.display(), i + 1, e))?;
+        replay(&host, &mut root, exchange)?;
+        replayed += 1;
+    }
+    // flush the partial batch the way batch_max_wait would
+    root.on_tick();
+
+    fs::create_dir_all(&output)?;
+    for old in batch_files(&output)? {
+        fs::remove_file(old)?;
+    }
+    let mut batches: Vec<(String, Value)> = Vec::new();
+    for call in host.take_http_calls() {
+        let name = match BATCH_FILES
+            .iter()
+            .find(|(path, _)| call.header(":path") == Some(path))
+        {
+            Some((_, name)) => name,
+            None => continue,
+        };
+        let count = batches
+            .iter()
+            .filter(|(file, _)| file.starts_with(&format!("{}-", name)))
+            .count();
+        batches.push((format!("{}-{:04}.json", name, count + 1), call.body_json()));
+    }
+    batches.sort_by(|a, b| a.0.cmp(&b.0));
+    for (file, batch) in &batches {
+        fs::write(
+            output.join(file),
+            serde_json::to_string_pretty(batch)? + "\n",
+        )?;
+    }
+    println!(
+        "replayed {} requests from {} into {} batches in {}",
+        replayed,
+        input.display(),
+        batches.len(),
+        output.display()
+    );
+
+    if let Some(expected) = expected {
+        let expected_files = batch_files(&expected)?;
+        if expected_files.len() != batches.len() {
+            return Err(format!(
+                "expected {} batches in {} but replay produced {}",
+                expected_files.len(),
+                expected.display(),
+                batches.len()
+            )
+            .into());
+        }
+        for (path, (file, batch)) in expected_files.iter().zip(&batches) {
+            let want: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+            if path.file_name() != Some(file.as_ref()) || &want != batch {
+                return Err(format!("replay output differs from {}", path.display()).into());
+            }
+        }
+        println!("replay output matches {}", expected.display());
+    }
+    Ok(())
+}